@@ -1,4 +1,7 @@
 use crate::config::MasterCfg;
+use crate::error::KasmError;
+use crate::gradevalue::GradeValue;
+use crate::late::Moment;
 use clap::*;
 use std::path::PathBuf;
 
@@ -14,6 +17,11 @@ pub struct UnpackFiles {
     /// Path to the .csv downloaded from moodle
     #[arg(short = 'c', long, value_name = "/path/to/csv")]
     pub moodle_csv: PathBuf,
+
+    /// Due date of the sheet, to find late submissions
+    /// (in the time zone of the csv)
+    #[arg(long, value_name = "YYYY-MM-DD HH:MM")]
+    pub due: Option<Moment>,
 }
 
 #[derive(Parser, Clone, Debug, Default)]
@@ -23,11 +31,31 @@ pub struct RepackDir {
     pub sheet_id: String,
 }
 
+/// Fetch Command struct. Without a sheet, id or pattern,
+/// `fetch` asks which assignment to fetch.
 #[derive(Parser, Clone, Debug, Default)]
 pub struct FetchCmd {
-    /// Sheet ID
-    #[arg(value_name = "sheet")]
+    /// Sheet ID (the first number in the assignment's name)
+    #[arg(value_name = "sheet", conflicts_with_all = ["assign_id", "pattern"])]
     pub sheet_id: Option<String>,
+
+    /// Moodle assignment id
+    #[arg(long = "assign-id", value_name = "id", conflicts_with = "pattern")]
+    pub assign_id: Option<u64>,
+
+    /// Fetch every assignment whose name matches this regex
+    ///
+    /// e.g. "Blatt 0[5-6]"
+    #[arg(long = "match", value_name = "expr")]
+    pub pattern: Option<String>,
+
+    /// Only list the course's assignments
+    #[arg(long, default_value_t = false)]
+    pub list: bool,
+
+    /// Print the list as JSON
+    #[arg(long, default_value_t = false, requires = "list")]
+    pub json: bool,
 }
 
 /// Grade Command struct. Identical to config::Grade, but
@@ -35,11 +63,12 @@ pub struct FetchCmd {
 /// the target variables.
 #[derive(Parser, Clone, Debug, Default)]
 pub struct GradeCmd {
-    /// The grade to assign the group, as Moodle wants it
+    /// The grade to assign the group
     ///
-    /// e.g. 10,5 or 10,0
-    #[arg(value_name = "grade")]
-    pub grade: String,
+    /// e.g. 10,5 or 10.5
+    /// Omitted when grading by task (the first argument is the target then).
+    #[arg(value_name = "grade", required_unless_present = "tasks")]
+    pub grade: Option<String>,
 
     /// ID of the group/person to grade.
     ///
@@ -50,6 +79,57 @@ pub struct GradeCmd {
     // note that this MUST come second if we want to omit/infer it...
     #[arg(value_name = "target_team")]
     pub target: Option<String>,
+
+    /// Textual feedback (Markdown) for the group/person
+    #[arg(short = 'm', long = "feedback", value_name = "text")]
+    pub feedback: Option<String>,
+
+    /// Points for a single task (can be repeated). The grade is
+    /// the sum of all tasks then.
+    ///
+    /// e.g. --task 2a=3,5
+    #[arg(short = 't', long = "task", value_name = "task=points", value_parser = parse_task_points)]
+    pub tasks: Vec<(String, GradeValue)>,
+
+    /// Grade only this member of the team (an id from `members` in
+    /// grades.toml), e.g. someone who didn't contribute
+    #[arg(long = "member", value_name = "id", conflicts_with = "tasks")]
+    pub member: Option<String>,
+}
+
+fn parse_task_points(s: &str) -> Result<(String, GradeValue), KasmError> {
+    let (task, points) = s
+        .split_once('=')
+        .ok_or_else(|| KasmError::Validation(format!("{:?} should look like task=points", s)))?;
+    Ok((task.trim().to_string(), points.parse()?))
+}
+
+#[derive(Parser, Clone, Debug, Default)]
+pub struct StatusCmd {
+    /// Sheet ID. Uses the unpacked sheet we're in if omitted.
+    #[arg(value_name = "sheet")]
+    pub sheet_id: Option<String>,
+}
+
+#[derive(Parser, Clone, Debug, Default)]
+pub struct SimilarityCmd {
+    /// Sheet IDs. Several sheets are also compared with each other.
+    /// Uses the unpacked sheet we're in if omitted.
+    #[arg(value_name = "sheet")]
+    pub sheet_ids: Vec<String>,
+
+    /// Report pairs of teams sharing at least this many percent of their code
+    /// (default: 50)
+    #[arg(short, long = "threshold", value_name = "percent")]
+    pub threshold: Option<u32>,
+
+    /// Skeleton file (or directory) handed out with the sheet. Code from it
+    /// doesn't count. Can be repeated.
+    ///
+    /// Relative to the current directory, unlike `templates` in kasm.toml
+    /// (which are relative to kasm.toml).
+    #[arg(long = "template", value_name = "path")]
+    pub templates: Vec<PathBuf>,
 }
 
 /// Push Command Struct. Basically tells us whether we're dry-running.
@@ -57,6 +137,10 @@ pub struct GradeCmd {
 pub struct PushCmd {
     #[arg(long = "dry-run", default_value_t = false)]
     pub dry_run: bool,
+
+    /// Also upload the feedback files (filtered like `repack` does)
+    #[arg(long = "files", default_value_t = false)]
+    pub files: bool,
 }
 
 /// First subcommand ("verb") found on the cmdline
@@ -67,7 +151,7 @@ pub enum Verb {
     /// Repack the zip to publish feedback/grades
     Repack(RepackDir),
     /// Initialize the master config file
-    Init(MasterCfg),
+    Init(Box<MasterCfg>),
     /// Grade team
     Grade(GradeCmd),
     /// Set up autofetch
@@ -76,6 +160,12 @@ pub enum Verb {
     Fetch(FetchCmd),
     /// Push grades to moodle
     Push(PushCmd),
+    /// Compare local grades with the ones on moodle
+    Diff,
+    /// Show the grading progress of a sheet
+    Status(StatusCmd),
+    /// Find teams with suspiciously similar submissions
+    Similarity(SimilarityCmd),
 }
 
 #[derive(Parser, Clone, Debug)]
@@ -1,20 +1,39 @@
-use std::{error::Error, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
 use clap::*;
 use log::debug;
 use serde::{Deserialize, Serialize};
 use strum::Display;
 
-use crate::gradingtable::GradingRecord;
+use crate::error::{KasmError, Result};
+use crate::gradevalue::GradeValue;
+use crate::gradingtable::{CsvHeaders, GradingRecord};
+use crate::hooks::Hooks;
+use crate::late::{LatePolicy, Moment, Penalty, Span};
+use crate::similarity::SimilarityCfg;
+use crate::template::Templates;
+use crate::token::TokenConfig;
 
 pub const MASTER_CFG_FILENAME: &str = "kasm.toml";
 pub const DEFAULT_GROUPS_REGEX: &str = r#"([0-9]{2}).+([0-9]{2})"#;
-pub const UNPACK_PATH_FILENAME_BASE: &str = "unpack_";
+pub const DEFAULT_MOODLE_URL: &str = "https://moodle.rwth-aachen.de";
+pub const DEFAULT_DOWNLOAD_JOBS: usize = 4;
 pub const UNPACK_CSV_FILENAME: &str = ".filtered.csv";
 pub const UNPACK_GRADES_FILENAME: &str = "grades.toml";
+pub const UNPACK_EXTRACTED_FILENAME: &str = ".extracted.toml";
+pub const UNPACK_MANIFEST_FILENAME: &str = ".fetched.toml";
+pub const FEEDBACK_FILENAME: &str = "feedback.md";
+pub const POINTS_FILENAME: &str = "points.md";
 
 /// Tells us whether the zip we're extracting contains groupped or individual
 /// submissions, as well as whether we want to repack it as one or the other.
+///
+/// Unpacking individuals and "repacking" them as groups regroups the students
+/// into their teams (using the csv's group column) while unpacking. The team
+/// grades and feedback get fanned back out to every member when repacking.
 #[derive(ValueEnum, Clone, Debug, Default, Display, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Structure {
     #[default]
@@ -38,6 +57,16 @@ pub struct MasterCfg {
     #[arg(long = "course-id", value_name = "12345678")]
     pub moodle_course_id: Option<String>,
 
+    /// Base URL of the Moodle instance (default: RWTH Moodle)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[arg(long = "moodle-url", value_name = "https://moodle.example.org")]
+    pub moodle_url: Option<String>,
+
+    /// Number of parallel downloads when fetching (default: 4)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(long = "download-jobs", value_name = "n")]
+    pub download_jobs: Option<usize>,
+
     /// Regex with for (group, team)
     #[arg(short = 'r', long = "regex", value_name = "expr", default_value = DEFAULT_GROUPS_REGEX)]
     pub groups_regex: String,
@@ -46,7 +75,8 @@ pub struct MasterCfg {
     #[arg(short = 'g', long = "group", value_name = "group id")]
     pub group: String,
 
-    /// Unzip nested zip files
+    /// Unzip nested zip files (next to the zip, into a directory of the same
+    /// name, plus a number if that is taken)
     #[arg(long, default_value_t = false)]
     pub recursive_unzip: bool,
 
@@ -59,6 +89,43 @@ pub struct MasterCfg {
 
     #[arg(long, value_name = "struct", default_value = "groups")]
     pub repack_structure: Structure,
+
+    /// Maximum grade. Only used if the assignment doesn't tell us.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[arg(long, value_name = "points")]
+    pub max_grade: Option<GradeValue>,
+
+    /// Custom grading worksheet headers. German and English
+    /// headers are detected automatically.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[clap(skip)]
+    pub csv_headers: Option<CsvHeaders>,
+
+    /// Penalty for late submissions. Copied into every new sheet,
+    /// where it can be changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[clap(skip)]
+    pub late_policy: Option<LatePolicy>,
+
+    /// Script hooks
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    #[clap(skip)]
+    pub hooks: Hooks,
+
+    /// Where to find the Moodle token (besides the keyring)
+    #[serde(default, skip_serializing_if = "TokenConfig::is_empty")]
+    #[clap(skip)]
+    pub token: TokenConfig,
+
+    /// Settings of `kasm similarity`
+    #[serde(default, skip_serializing_if = "SimilarityCfg::is_empty")]
+    #[clap(skip)]
+    pub similarity: SimilarityCfg,
+
+    /// Naming templates for directories and files
+    #[serde(default, skip_serializing_if = "Templates::is_default")]
+    #[clap(skip)]
+    pub templates: Templates,
 }
 
 /// Where the files came from
@@ -91,6 +158,22 @@ pub struct Grades {
     /// e.g. 04
     pub sheet_id: String,
 
+    /// Maximum grade of the assignment (from Moodle or the csv)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_grade: Option<GradeValue>,
+
+    /// Tasks of the sheet, for grading task by task
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<Task>,
+
+    /// Due date of the assignment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due: Option<Moment>,
+
+    /// Penalty for late submissions. None means grades are pushed/repacked as they are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub late_policy: Option<LatePolicy>,
+
     /// Grade maps
     /// target (matrnr/group_id) -> grade
     pub map: Vec<Grade>,
@@ -116,11 +199,214 @@ impl Grades {
             .collect()
     }
 
+    // Finds the grading record of a single student and overwrites its
+    // grade using the current object's grades
+    pub fn collect_student(&self, gt: &[GradingRecord], key: &str) -> Option<GradingRecord> {
+        let mut new_gr = gt.iter().find(|&gr| gr.student_key() == key)?.clone();
+        new_gr.grade = self.find_grade_for_target(key)?;
+        Some(new_gr)
+    }
+
+    // Generates a vector of grading records for every member of a
+    // (regrouped) team, overwriting their grades with the team's grade
+    pub fn collect_members(&self, gt: &[GradingRecord], target: &str) -> Vec<GradingRecord> {
+        let Some(grade) = self.map.iter().find(|&g| g.target == target) else {
+            return Vec::new();
+        };
+        let members = grade.members.clone().unwrap_or_default();
+
+        gt.iter()
+            .filter(|&gr| members.iter().any(|m| m == gr.participant_id()))
+            .map(|gr| {
+                let mut new_gr = gr.clone();
+                new_gr.grade = self
+                    .final_grade_of(grade, gr.participant_id())
+                    .map(|g| g.to_csv())
+                    .unwrap_or_default();
+                new_gr
+            })
+            .collect()
+    }
+
+    /// Textual feedback for `target` as Markdown, either from grades.toml
+    /// or from a feedback.md inside the target's directory
+    pub fn feedback_for(&self, target: &str) -> Result<Option<String>> {
+        if let Some(feedback) = self
+            .map
+            .iter()
+            .find(|&g| g.target == target)
+            .and_then(|g| g.feedback.clone())
+        {
+            return Ok(Some(feedback));
+        }
+
+        let feedback_file = self
+            .location
+            .parent()
+            .map(|unpack_path| unpack_path.join(target).join(FEEDBACK_FILENAME));
+        match feedback_file {
+            Some(path) if path.is_file() => Ok(Some(std::fs::read_to_string(path)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Points per task of `target` as a Markdown table
+    /// (None if it wasn't graded task by task)
+    pub fn breakdown_for(&self, target: &str) -> Option<String> {
+        let gd = self.map.iter().find(|&g| g.target == target)?;
+        if gd.tasks.is_empty() {
+            return None;
+        }
+
+        let mut table = String::from("| Task | Points |\n|:-----|-------:|\n");
+        for task in &self.tasks {
+            let points = gd.tasks.get(&task.name).copied().unwrap_or_default();
+            table += &format!("| {} | {} / {} |\n", task.name, points, task.max);
+        }
+        let total = gd.grade.unwrap_or_default();
+        match self.tasks.iter().map(|t| t.max).sum::<GradeValue>() {
+            max if max > GradeValue::ZERO => {
+                table += &format!("| **Total** | **{} / {}** |\n", total, max)
+            }
+            _ => table += &format!("| **Total** | **{}** |\n", total),
+        }
+
+        Some(table)
+    }
+
+    /// Textual feedback for `target` (including the points
+    /// per task), rendered to HTML
+    pub fn feedback_html_for(&self, target: &str) -> Result<Option<String>> {
+        let feedback = self
+            .feedback_for(target)?
+            .filter(|md| !md.trim().is_empty());
+        let late = self
+            .map
+            .iter()
+            .find(|&g| g.target == target)
+            .filter(|&g| g.grade.is_some() && self.penalty(g).is_some_and(|p| p.percent > 0))
+            .and_then(|g| self.late_note(g))
+            .map(|note| format!("*{}*\n", note));
+        let parts: Vec<String> = [self.breakdown_for(target), late, feedback]
+            .into_iter()
+            .flatten()
+            .collect();
+        if parts.is_empty() {
+            return Ok(None);
+        }
+        let md = parts.join("\n");
+
+        let mut html = String::new();
+        let parser = pulldown_cmark::Parser::new_ext(&md, pulldown_cmark::Options::ENABLE_TABLES);
+        pulldown_cmark::html::push_html(&mut html, parser);
+        Ok(Some(html.trim_end().to_string()))
+    }
+
+    pub fn task(&self, name: &str) -> Option<&Task> {
+        self.tasks.iter().find(|t| t.name == name)
+    }
+
+    /// The grade of `target` (after the late penalty), formatted for the grading worksheet
     pub fn find_grade_for_target(&self, target: &str) -> Option<String> {
         self.map
             .iter()
             .find(|&g| g.target == target)
-            .map(|g| g.grade.clone())
+            .map(|g| self.final_grade(g).map(|g| g.to_csv()).unwrap_or_default())
+    }
+
+    /// How late `gd` submitted (None if on time or unknown)
+    pub fn late_by(&self, gd: &Grade) -> Option<Span> {
+        let due = self.due?.checked_add(gd.extension.unwrap_or_default())?;
+        gd.submitted?.after(due)
+    }
+
+    /// What `gd`'s lateness costs under the sheet's policy
+    pub fn penalty(&self, gd: &Grade) -> Option<Penalty> {
+        self.late_policy.as_ref()?.penalty(self.late_by(gd)?)
+    }
+
+    /// The grade that gets pushed/repacked, i.e. after the late penalty
+    pub fn final_grade(&self, gd: &Grade) -> Option<GradeValue> {
+        self.apply_penalty(gd, gd.grade)
+    }
+
+    /// Like [`Grades::final_grade`], but for a single member (who might
+    /// have gotten a different grade than the rest of the team)
+    pub fn final_grade_of(&self, gd: &Grade, member: &str) -> Option<GradeValue> {
+        self.apply_penalty(gd, gd.grade_of(member))
+    }
+
+    /// The final grade of every member that has one (member -> grade)
+    pub fn final_member_grades(&self, gd: &Grade) -> BTreeMap<String, GradeValue> {
+        gd.members
+            .iter()
+            .flatten()
+            .filter_map(|member| Some((member.clone(), self.final_grade_of(gd, member)?)))
+            .collect()
+    }
+
+    fn apply_penalty(&self, gd: &Grade, grade: Option<GradeValue>) -> Option<GradeValue> {
+        match self.penalty(gd) {
+            Some(penalty) => grade.map(|g| g.minus_percent(penalty.percent)),
+            None => grade,
+        }
+    }
+
+    /// Describes `gd`'s lateness (and its penalty) for humans
+    pub fn late_note(&self, gd: &Grade) -> Option<String> {
+        let late_by = self.late_by(gd)?;
+        Some(match self.penalty(gd) {
+            Some(penalty) if penalty.percent > 0 => {
+                format!("submitted {} late: -{}%", late_by, penalty.percent)
+            }
+            _ => format!("submitted {} late", late_by),
+        })
+    }
+
+    /// Maximum grade of the assignment, falling back to the master config
+    pub fn max_grade(&self, master: &MasterCfg) -> Option<GradeValue> {
+        self.max_grade.or(master.max_grade)
+    }
+
+    /// Writes the grades back to where we found them
+    pub fn write(&self) -> Result<()> {
+        std::fs::write(&self.location, toml::to_string_pretty(self)?)
+            .map_err(KasmError::io_at(&self.location))
+    }
+
+    /// Checks all grades against the maximum grade
+    pub fn validate(&self, master: &MasterCfg) -> Result<()> {
+        let max = self.max_grade(master);
+        for task in &self.tasks {
+            if task.max > max.unwrap_or(task.max) {
+                return Err(KasmError::Validation(format!(
+                    "task {} is worth more than the whole sheet",
+                    task.name
+                )));
+            }
+        }
+
+        for gd in &self.map {
+            if let Some(grade) = gd.grade {
+                grade
+                    .validate(max)
+                    .map_err(|e| KasmError::Validation(format!("{} ({})", e, gd.target)))?;
+            }
+
+            let members = gd.members.as_deref().unwrap_or_default();
+            for (member, grade) in &gd.member_grades {
+                if !members.contains(member) {
+                    return Err(KasmError::Validation(format!(
+                        "{} isn't a member of {}",
+                        member, gd.target
+                    )));
+                }
+                grade.validate(max).map_err(|e| {
+                    KasmError::Validation(format!("{} ({}, {})", e, gd.target, member))
+                })?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -129,29 +415,222 @@ pub struct Grade {
     /// Full team name/matrnr that the grade corresponds to
     pub target: String,
 
-    /// Internal Moodle ID
+    /// Internal Moodle ID: the participant id of a member (csv-workflow)
+    /// or the group id (auto-workflow)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub internal_id: Option<String>,
 
-    /// Member `userid`s to push grades to. Applies only to auto-workflow
+    /// Member `userid`s to push grades to (auto-workflow), or participant ids
+    /// of the students merged into this team (regrouped csv-workflow)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub members: Option<Vec<String>>,
 
-    /// The grade to assign the group, should be formatted
-    /// as Moodle wants it to be formatted
-    /// e.g. 10,5 or 10,0
-    pub grade: String,
+    /// The grade to assign the group (if graded yet).
+    /// Either decimal separator works, e.g. 10,5 or 10.5
+    #[serde(default, with = "crate::gradevalue::optional")]
+    pub grade: Option<GradeValue>,
+
+    /// Textual feedback (Markdown). Takes precedence over
+    /// a `feedback.md` in the team's directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feedback: Option<String>,
+
+    /// Time of the (last) submission
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submitted: Option<Moment>,
+
+    /// Extra time this target got (moves the due date)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension: Option<Span>,
+
+    /// The grade as it was last pushed to Moodle (auto-workflow)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pushed: Option<GradeValue>,
+
+    /// The grades as they were last pushed to single members (member -> grade),
+    /// if the team was pushed member by member
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pushed_members: BTreeMap<String, GradeValue>,
+
+    /// Points per task (task name -> points)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tasks: BTreeMap<String, GradeValue>,
+
+    /// Names and participant ids of the members (auto-workflow, user id -> member).
+    /// Lets `repack` name the members' feedback directories without a csv.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub participants: BTreeMap<String, Member>,
+
+    /// Grades of single members that differ from the team's grade
+    /// (member -> grade), e.g. for someone who didn't contribute
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub member_grades: BTreeMap<String, GradeValue>,
+}
+
+impl Grade {
+    /// The participant id feedback zips use for this team (auto-workflow),
+    /// i.e. the one of its first member who has one
+    pub fn participant_id(&self) -> Option<&str> {
+        self.participants
+            .values()
+            .find_map(|member| member.participant_id.as_deref())
+    }
+
+    /// The grade of a single member (the team's grade unless overridden)
+    pub fn grade_of(&self, member: &str) -> Option<GradeValue> {
+        self.member_grades.get(member).copied().or(self.grade)
+    }
+}
+
+/// A member of a fetched team
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+
+    /// Participant id in Moodle's feedback zips
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub participant_id: Option<String>,
+}
+
+/// A task (exercise) of a sheet
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Task {
+    /// e.g. 2a
+    pub name: String,
+
+    /// Maximum points
+    pub max: GradeValue,
+}
+
+/// Files that were extracted out of nested archives
+/// (relative to the unpack directory). They always end up in a subdirectory
+/// of a team's directory, so `repack` never ships them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Extracted {
+    pub files: Vec<PathBuf>,
+
+    /// The directory each archive was extracted into (archive -> directory)
+    #[serde(default)]
+    pub archives: BTreeMap<PathBuf, PathBuf>,
+}
+
+impl Extracted {
+    /// Reads the list of extracted files. No list means nothing was extracted.
+    pub fn read(unpack_path: &Path) -> Result<Extracted> {
+        let path = unpack_path.join(UNPACK_EXTRACTED_FILENAME);
+        if !path.is_file() {
+            return Ok(Extracted::default());
+        }
+
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn write(&self, unpack_path: &Path) -> Result<()> {
+        std::fs::write(
+            unpack_path.join(UNPACK_EXTRACTED_FILENAME),
+            toml::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    /// The directory `archive` was extracted into (if it was)
+    pub fn dir_of(&self, unpack_path: &Path, archive: &Path) -> Option<&Path> {
+        let rel = archive.strip_prefix(unpack_path).ok()?;
+        self.archives.get(rel).map(|dir| dir.as_path())
+    }
+
+    /// Deletes and forgets everything extracted out of `archive`, so that it
+    /// can be extracted again. Files that weren't extracted (e.g. feedback
+    /// the tutor put next to them) are kept.
+    pub fn remove(&mut self, unpack_path: &Path, archive: &Path) -> Result<()> {
+        let Some(dir) = self.dir_of(unpack_path, archive).map(Path::to_path_buf) else {
+            return Ok(());
+        };
+        let dir_path = unpack_path.join(&dir);
+
+        for file in self.files.iter().filter(|f| f.starts_with(&dir)) {
+            let path = unpack_path.join(file);
+            if path.is_file() {
+                std::fs::remove_file(&path).map_err(KasmError::io_at(&path))?;
+            }
+
+            // Clean up the directories that are empty now
+            for parent in path
+                .ancestors()
+                .skip(1)
+                .take_while(|p| p.starts_with(&dir_path))
+            {
+                if std::fs::remove_dir(parent).is_err() {
+                    break;
+                }
+            }
+        }
+
+        self.files.retain(|f| !f.starts_with(&dir));
+        self.archives
+            .retain(|archive, extracted_into| !archive.starts_with(&dir) && *extracted_into != dir);
+        Ok(())
+    }
+}
+
+/// Submission files `fetch` downloaded (relative to the unpack directory).
+/// Fetching again only downloads new or changed files.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FetchManifest {
+    #[serde(default)]
+    pub files: Vec<FetchedFile>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FetchedFile {
+    pub path: PathBuf,
+    pub filesize: u64,
+    pub timemodified: i64,
+}
+
+impl FetchManifest {
+    /// Reads the manifest. No manifest means nothing was fetched yet.
+    pub fn read(unpack_path: &Path) -> Result<FetchManifest> {
+        let path = unpack_path.join(UNPACK_MANIFEST_FILENAME);
+        if !path.is_file() {
+            return Ok(FetchManifest::default());
+        }
+
+        Ok(toml::from_str(
+            &std::fs::read_to_string(&path).map_err(KasmError::io_at(&path))?,
+        )?)
+    }
+
+    pub fn write(&self, unpack_path: &Path) -> Result<()> {
+        let path = unpack_path.join(UNPACK_MANIFEST_FILENAME);
+        std::fs::write(&path, toml::to_string_pretty(self)?).map_err(KasmError::io_at(&path))
+    }
+
+    pub fn get(&self, rel: &Path) -> Option<&FetchedFile> {
+        self.files.iter().find(|f| f.path == rel)
+    }
+
+    /// Adds `file`, replacing an older version of it
+    pub fn record(&mut self, file: FetchedFile) {
+        match self.files.iter_mut().find(|f| f.path == file.path) {
+            Some(known) => *known = file,
+            None => self.files.push(file),
+        }
+    }
 }
 
 /// Walks upwards the directory tree and tries to find `filename`
-fn find_in_preceding_dir_tree(filename: &str) -> Result<PathBuf, Box<dyn Error>> {
+fn find_in_preceding_dir_tree(filename: &str) -> Result<PathBuf> {
     let mut path = std::env::current_dir()?;
 
     while !path.join(filename).is_file() {
         if let Some(parent) = path.parent() {
             path = parent.to_path_buf();
         } else {
-            return Err("couldn't find file".into());
+            return Err(KasmError::Config(format!(
+                "couldn't find {} in this or any parent directory",
+                filename
+            )));
         }
     }
 
@@ -160,10 +639,40 @@ fn find_in_preceding_dir_tree(filename: &str) -> Result<PathBuf, Box<dyn Error>>
 }
 
 impl MasterCfg {
+    /// Base URL of the Moodle instance
+    pub fn moodle_url(&self) -> &str {
+        self.moodle_url.as_deref().unwrap_or(DEFAULT_MOODLE_URL)
+    }
+
+    /// Number of parallel downloads (at least one)
+    pub fn download_jobs(&self) -> usize {
+        self.download_jobs.unwrap_or(DEFAULT_DOWNLOAD_JOBS).max(1)
+    }
+
+    /// Whether individual submissions get merged into team directories
+    pub fn regroups(&self) -> bool {
+        self.unpack_structure == Structure::Individuals
+            && self.repack_structure == Structure::Groups
+    }
+
+    /// Structure of the unpacked directories, i.e. whether we grade
+    /// teams or single students
+    pub fn grading_structure(&self) -> Structure {
+        match (&self.unpack_structure, &self.repack_structure) {
+            (Structure::Individuals, Structure::Individuals) => Structure::Individuals,
+            _ => Structure::Groups,
+        }
+    }
+
     /// Finds/parses the master config
-    pub fn resolve() -> Result<MasterCfg, Box<dyn Error>> {
+    pub fn resolve() -> Result<MasterCfg> {
         let cfg_path = find_in_preceding_dir_tree(MASTER_CFG_FILENAME)?;
-        let mut cfg = toml::from_str::<MasterCfg>(&std::fs::read_to_string(cfg_path.clone())?)?;
+        let mut cfg = toml::from_str::<MasterCfg>(
+            &std::fs::read_to_string(&cfg_path).map_err(KasmError::io_at(&cfg_path))?,
+        )
+        .map_err(|e| KasmError::Config(format!("{:?}: {}", cfg_path, e)))?;
+        cfg.templates.validate()?;
+        cfg.similarity.validate()?;
         cfg.location = cfg_path;
         Ok(cfg)
     }
@@ -171,9 +680,26 @@ impl MasterCfg {
 
 impl Grades {
     /// Finds/parses the nested grades config
-    pub fn resolve() -> Result<Grades, Box<dyn Error>> {
-        let cfg_path = find_in_preceding_dir_tree(UNPACK_GRADES_FILENAME)?;
-        let mut cfg = toml::from_str::<Grades>(&std::fs::read_to_string(cfg_path.clone())?)?;
+    pub fn resolve() -> Result<Grades> {
+        Grades::read(find_in_preceding_dir_tree(UNPACK_GRADES_FILENAME)?)
+    }
+
+    /// Parses the grades config of the sheet `sheet_id`
+    /// (in the master config's directory)
+    pub fn for_sheet(master: &MasterCfg, sheet_id: &str) -> Result<Grades> {
+        let master_dir = master.location.parent().unwrap_or(Path::new("."));
+        Grades::read(
+            master_dir
+                .join(master.templates.unpack_dir(sheet_id)?)
+                .join(UNPACK_GRADES_FILENAME),
+        )
+    }
+
+    pub fn read(cfg_path: PathBuf) -> Result<Grades> {
+        let mut cfg = toml::from_str::<Grades>(
+            &std::fs::read_to_string(&cfg_path).map_err(KasmError::io_at(&cfg_path))?,
+        )
+        .map_err(|e| KasmError::Config(format!("{:?}: {}", cfg_path, e)))?;
         cfg.location = cfg_path;
         Ok(cfg)
     }
@@ -0,0 +1,145 @@
+use std::{fmt::Display, path::PathBuf};
+
+use crate::moodle::MoodleError;
+
+/// Everything that can go wrong in kasm
+#[derive(Debug)]
+pub enum KasmError {
+    /// Missing or malformed kasm.toml/grades.toml (or other settings)
+    Config(String),
+    /// Reading or writing a file failed
+    Io {
+        path: Option<PathBuf>,
+        source: std::io::Error,
+    },
+    /// The grading worksheet couldn't be read or written
+    Csv(csv::Error),
+    /// A submission/feedback zip couldn't be read or written
+    Zip(zip::result::ZipError),
+    /// Talking to Moodle failed
+    Moodle(MoodleError),
+    /// The user asked for something that doesn't make sense
+    /// (unknown target, invalid grade, broken template...)
+    Validation(String),
+    /// A script hook failed
+    Hook(String),
+    /// An interactive prompt was cancelled or failed
+    Prompt(inquire::InquireError),
+    /// Nothing went wrong, but there's work left (e.g. ungraded teams)
+    Incomplete(String),
+}
+
+pub type Result<T> = std::result::Result<T, KasmError>;
+
+impl KasmError {
+    /// Process exit code for this kind of error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            KasmError::Config(_) => 10,
+            KasmError::Io { .. } => 11,
+            KasmError::Csv(_) => 12,
+            KasmError::Zip(_) => 13,
+            KasmError::Moodle(_) => 14,
+            KasmError::Validation(_) => 15,
+            KasmError::Hook(_) => 16,
+            KasmError::Prompt(_) => 17,
+            KasmError::Incomplete(_) => 1,
+        }
+    }
+
+    /// Attaches the path an IO error happened at
+    pub fn io_at(path: impl Into<PathBuf>) -> impl FnOnce(std::io::Error) -> KasmError {
+        let path = path.into();
+        move |source| KasmError::Io {
+            path: Some(path),
+            source,
+        }
+    }
+}
+
+impl Display for KasmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KasmError::Config(msg) => write!(f, "config error: {}", msg),
+            KasmError::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{:?}: {}", path, source),
+            KasmError::Io { path: None, source } => write!(f, "{}", source),
+            KasmError::Csv(e) => write!(f, "csv error: {}", e),
+            KasmError::Zip(e) => write!(f, "zip error: {}", e),
+            KasmError::Moodle(e) => write!(f, "{}", e),
+            KasmError::Validation(msg) => write!(f, "{}", msg),
+            KasmError::Hook(msg) => write!(f, "{}", msg),
+            KasmError::Incomplete(msg) => write!(f, "{}", msg),
+            KasmError::Prompt(e) => write!(f, "prompt failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KasmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KasmError::Io { source, .. } => Some(source),
+            KasmError::Csv(e) => Some(e),
+            KasmError::Zip(e) => Some(e),
+            KasmError::Moodle(e) => Some(e),
+            KasmError::Prompt(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for KasmError {
+    fn from(source: std::io::Error) -> Self {
+        KasmError::Io { path: None, source }
+    }
+}
+
+impl From<csv::Error> for KasmError {
+    fn from(e: csv::Error) -> Self {
+        KasmError::Csv(e)
+    }
+}
+
+impl From<zip::result::ZipError> for KasmError {
+    fn from(e: zip::result::ZipError) -> Self {
+        KasmError::Zip(e)
+    }
+}
+
+impl From<MoodleError> for KasmError {
+    fn from(e: MoodleError) -> Self {
+        KasmError::Moodle(e)
+    }
+}
+
+impl From<inquire::InquireError> for KasmError {
+    fn from(e: inquire::InquireError) -> Self {
+        KasmError::Prompt(e)
+    }
+}
+
+impl From<toml::de::Error> for KasmError {
+    fn from(e: toml::de::Error) -> Self {
+        KasmError::Config(e.to_string())
+    }
+}
+
+impl From<toml::ser::Error> for KasmError {
+    fn from(e: toml::ser::Error) -> Self {
+        KasmError::Config(e.to_string())
+    }
+}
+
+impl From<regex::Error> for KasmError {
+    fn from(e: regex::Error) -> Self {
+        KasmError::Config(format!("invalid regex: {}", e))
+    }
+}
+
+impl From<keyring::Error> for KasmError {
+    fn from(e: keyring::Error) -> Self {
+        KasmError::Config(format!("keyring: {}", e))
+    }
+}
@@ -1,18 +1,53 @@
-use serde_json::Value;
-use std::{collections::HashMap, error::Error, path::PathBuf, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+};
 
-use crate::config::{Grade, Grades, MasterCfg, UNPACK_GRADES_FILENAME, UNPACK_PATH_FILENAME_BASE};
-use log::{error, info, warn};
+use crate::args::FetchCmd;
+use crate::config::{
+    Extracted, FetchManifest, FetchedFile, Grade, Grades, MasterCfg, Member, Structure,
+    UNPACK_GRADES_FILENAME,
+};
+use crate::error::{KasmError, Result};
+use crate::gradevalue::GradeValue;
+use crate::hooks::{HookEnv, HookOutcome, HookPoint};
+use crate::late::Moment;
+use crate::moodle::{
+    Assignment, AssignmentsResponse, GradesResponse, MoodleClient, MoodleUrls, Participant,
+    ParticipantDetails, SubmissionFile, SubmissionsResponse,
+};
+use crate::repack::RepackFilter;
+use crate::status::print_table;
+use crate::token::{self, TokenConfig, TokenSource, KEYRING_SERVICE_NAME, TOKEN_ENV_VAR};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use serde::Serialize;
 
-const KEYRING_SERVICE_NAME: &str = "kasm-moodle-token";
-static MOODLE_REST_URL: &str = "https://moodle.rwth-aachen.de/webservice/rest/server.php";
+const DEFAULT_TOKEN_FILE: &str = "~/.config/kasm/token";
 
-pub fn setup(master: &MasterCfg) -> core::result::Result<(), Box<dyn Error>> {
+fn prompt_token() -> Result<String> {
+    Ok(inquire::Password::new("Moodle Token (won't be echoed): ")
+        .without_confirmation()
+        .prompt()?)
+}
+
+pub fn setup(master: &MasterCfg) -> Result<()> {
     let overwrite_course;
 
     let mut new_master = master.clone();
     let user = whoami::username();
 
+    let url = inquire::Text::new("Moodle URL > ")
+        .with_default(master.moodle_url())
+        .prompt()?;
+    let urls = MoodleUrls::new(&url)?;
+    new_master.moodle_url = Some(urls.base().to_string());
+    info!("using moodle instance at {}", urls.base());
+
     if let Some(course) = &master.moodle_course_id {
         info!("the saved course id is {}.", course);
         print!("overwrite? (y/N) > ");
@@ -29,38 +64,132 @@ pub fn setup(master: &MasterCfg) -> core::result::Result<(), Box<dyn Error>> {
 
     info!(
         "using course id = {{{}}}",
-        new_master.moodle_course_id.as_ref().unwrap()
+        new_master.moodle_course_id.as_deref().unwrap_or_default()
     );
 
-    let entry = keyring::Entry::new(KEYRING_SERVICE_NAME, &user)?;
-    let overwrite_token = match entry.get_password() {
-        Err(keyring::Error::NoEntry) => true,
-        Err(keyring::Error::Ambiguous(a)) => {
-            error!("{} is ambiguous in your keyring", KEYRING_SERVICE_NAME);
-            return Err(keyring::Error::Ambiguous(a).into());
+    let sources = vec![
+        TokenSource::Keyring,
+        TokenSource::File,
+        TokenSource::Command,
+        TokenSource::Env,
+    ];
+    let current = if master.token.file.is_some() {
+        1
+    } else if master.token.command.is_some() {
+        2
+    } else {
+        0
+    };
+    let source = inquire::Select::new("Where should the token come from?", sources)
+        .with_starting_cursor(current)
+        .prompt()?;
+
+    // Only the chosen source stays configured
+    new_master.token = TokenConfig::default();
+    let mut token_file = None;
+    let mut keyring_entry = None;
+    let token = match source {
+        TokenSource::Keyring => {
+            let entry = token::keyring_entry()?;
+            let overwrite_token = match entry.get_password() {
+                Err(keyring::Error::NoEntry) => true,
+                Err(keyring::Error::Ambiguous(_)) => {
+                    return Err(KasmError::Config(format!(
+                        "{} is ambiguous in your keyring",
+                        KEYRING_SERVICE_NAME
+                    )));
+                }
+                Err(e) => return Err(e.into()),
+                Ok(_) => {
+                    info!(
+                        "your keyring already contains an entry for {}",
+                        KEYRING_SERVICE_NAME
+                    );
+                    inquire::Confirm::new("overwrite? (y/n) > ")
+                        .prompt_skippable()?
+                        .unwrap_or(false)
+                }
+            };
+
+            if overwrite_token {
+                let token = prompt_token()?;
+                keyring_entry = Some(entry);
+                token
+            } else {
+                entry.get_password()?
+            }
         }
-        Err(e) => return Err(e.into()),
-        Ok(_) => {
-            info!(
-                "your keyring already contains an entry for {}",
-                KEYRING_SERVICE_NAME
+        TokenSource::File => {
+            let default = master
+                .token
+                .file
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_TOKEN_FILE));
+            let file = PathBuf::from(
+                inquire::Text::new("Token file > ")
+                    .with_default(&default.to_string_lossy())
+                    .prompt()?,
             );
-            inquire::Confirm::new("overwrite? (y/n) > ")
-                .prompt_skippable()?
-                .unwrap_or(false)
+            let path = token::token_file_path(&new_master, &file);
+            new_master.token.file = Some(file);
+
+            let keep = path.is_file()
+                && inquire::Confirm::new(&format!("{:?} exists. keep its token? (y/n) > ", path))
+                    .prompt_skippable()?
+                    .unwrap_or(true);
+            if keep {
+                token::read_file(&path)?
+            } else {
+                token_file = Some(path);
+                prompt_token()?
+            }
+        }
+        TokenSource::Command => {
+            let mut prompt = inquire::Text::new("Command printing the token > ");
+            if let Some(ref cmd) = master.token.command {
+                prompt = prompt.with_default(cmd);
+            }
+            let cmd = prompt.prompt()?;
+            new_master.token.command = Some(cmd.clone());
+            token::run_command(&cmd)?
         }
+        TokenSource::Env => match token::from_env() {
+            Some(token) => token,
+            None => prompt_token()?,
+        },
     };
 
-    if overwrite_token {
-        let token = inquire::Password::new("Moodle Token (won't be echoed): ")
-            .without_confirmation()
-            .prompt()?;
+    let site = MoodleClient::new(urls, token.clone())?.site_info()?;
+    info!("connected to {} as {}", site.sitename, site.fullname);
+
+    if let Some(entry) = keyring_entry {
         info!(
             "saving token to keyring ({}, {})",
             KEYRING_SERVICE_NAME, user
         );
         entry.set_password(&token)?;
     }
+    if let Some(path) = token_file {
+        info!("saving token to {:?}", path);
+        token::write_file(&path, &token)?;
+    }
+
+    match source {
+        TokenSource::Env if token::from_env().is_none() => {
+            info!(
+                "the token was only checked, not saved. set {} to it before running kasm",
+                TOKEN_ENV_VAR
+            )
+        }
+        TokenSource::Env => {}
+        _ if token::from_env().is_some() => {
+            warn!(
+                "{} is set and takes precedence over the {}",
+                TOKEN_ENV_VAR, source
+            )
+        }
+        _ => {}
+    }
 
     std::fs::write(
         new_master.location.clone(),
@@ -75,211 +204,334 @@ pub struct SubmissionFileMap {
     pub dl_url: String,
     pub group_id: String,
     pub group_name: String,
+    pub filesize: u64,
+    pub timemodified: i64,
+}
+
+/// Everything a team submitted
+#[derive(Debug, Clone, Default)]
+pub struct TeamSubmission {
+    pub files: Vec<SubmissionFile>,
+    /// Time of the last change
+    pub submitted: Option<Moment>,
+}
+
+/// Whom a grade gets pushed to
+#[derive(Debug, Clone, Copy)]
+enum PushTo<'a> {
+    /// A member of the team, the grade applies to the whole team
+    Team(&'a str),
+    /// Only this member
+    Member(&'a str),
+}
+
+/// The groups of an assignment and their members
+#[derive(Debug, Clone, Default)]
+pub struct GroupMappings {
+    /// group id -> group name
+    pub names: HashMap<String, String>,
+    /// group id -> member user ids
+    pub members: HashMap<String, Vec<String>>,
+    /// user id -> name and participant id
+    pub people: HashMap<String, Member>,
+}
+
+/// The sheet id is the (first) number in the assignment's name
+fn sheet_id_of(name: &str) -> String {
+    regex::Regex::new(r"(\d?\d)")
+        .ok()
+        .and_then(|nr_regex| nr_regex.captures(name)?.get(1))
+        .map(|m| m.as_str())
+        .unwrap_or_else(|| name.split(' ').next_back().unwrap_or(name))
+        .to_string()
+}
+
+/// "5" and "05" are the same sheet
+fn same_sheet(a: &str, b: &str) -> bool {
+    match (a.parse::<u32>(), b.parse::<u32>()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// An entry of `kasm fetch --list`
+#[derive(Debug, Serialize)]
+struct ListedAssignment<'a> {
+    id: u64,
+    sheet: String,
+    name: &'a str,
+    max_grade: Option<String>,
+}
+
+fn list_assignments(assignments: &HashMap<String, Assignment>, json: bool) -> Result<()> {
+    let mut listed: Vec<ListedAssignment> = assignments
+        .values()
+        .map(|a| ListedAssignment {
+            id: a.id,
+            sheet: sheet_id_of(&a.name),
+            name: &a.name,
+            max_grade: GradeValue::from_f64(a.grade)
+                .filter(|&max| max > GradeValue::ZERO)
+                .map(|max| max.to_string()),
+        })
+        .collect();
+    listed.sort_by(|a, b| a.name.cmp(b.name));
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&listed)
+                .map_err(|e| KasmError::Validation(e.to_string()))?
+        );
+        return Ok(());
+    }
+
+    let rows: Vec<Vec<String>> = listed
+        .iter()
+        .map(|a| {
+            vec![
+                a.id.to_string(),
+                a.sheet.clone(),
+                a.name.to_string(),
+                a.max_grade.clone().unwrap_or_else(|| "-".into()),
+            ]
+        })
+        .collect();
+    print_table(&["id", "sheet", "name", "max grade"], &rows);
+    Ok(())
+}
+
+/// How a local grade relates to the one on Moodle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum GradeChange {
+    /// Graded locally, but not on Moodle
+    Added,
+    Changed,
+    Unchanged,
+    /// Graded on Moodle, but not locally
+    Removed,
+    /// Neither here nor there
+    Ungraded,
+}
+
+impl GradeChange {
+    fn of(local: Option<GradeValue>, remote: Option<GradeValue>) -> GradeChange {
+        match (local, remote) {
+            (Some(_), None) => GradeChange::Added,
+            (Some(l), Some(r)) if l == r => GradeChange::Unchanged,
+            (Some(_), Some(_)) => GradeChange::Changed,
+            (None, Some(_)) => GradeChange::Removed,
+            (None, None) => GradeChange::Ungraded,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            GradeChange::Added => "added",
+            GradeChange::Changed => "changed",
+            GradeChange::Unchanged => "unchanged",
+            GradeChange::Removed => "removed",
+            GradeChange::Ungraded => "-",
+        }
+    }
 }
 
 pub struct MoodleFetcher {
     pub course_id: String,
     pub config: MasterCfg,
-    token: String,
+    client: MoodleClient,
 }
 
 impl MoodleFetcher {
-    pub fn new(config: &MasterCfg) -> MoodleFetcher {
-        let entry =
-            keyring::Entry::new(KEYRING_SERVICE_NAME, &whoami::username()).unwrap_or_else(|e| {
-                error!("{}", e);
-                error!("run setup-fetch first!");
-                panic!();
-            });
+    pub fn new(config: &MasterCfg) -> Result<MoodleFetcher> {
+        let not_set_up =
+            |what: &str| KasmError::Config(format!("{} is missing. run setup-fetch first!", what));
+
+        let course_id = config
+            .moodle_course_id
+            .clone()
+            .ok_or_else(|| not_set_up("the moodle course id"))?;
+
+        let token = token::resolve(config)?;
 
-        MoodleFetcher {
+        Ok(MoodleFetcher {
             config: config.clone(),
-            course_id: config
-                .moodle_course_id
-                .as_ref()
-                .unwrap_or_else(|| {
-                    error!("run fetch-setup first!");
-                    panic!()
-                })
-                .clone(),
-            token: entry.get_password().unwrap(),
-        }
+            course_id,
+            client: MoodleClient::new(MoodleUrls::new(config.moodle_url())?, token)?,
+        })
     }
 
-    pub fn fetch_directory(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
-        let resp = reqwest::blocking::Client::new()
-            .get(MOODLE_REST_URL)
-            .query(&[
-                ("moodlewsrestformat", "json"),
-                ("wsfunction", "mod_assign_get_assignments"),
-                ("wstoken", &self.token),
-                ("courseids[0]", &self.course_id),
-            ])
-            .send()?;
-
-        let data: serde_json::Value = serde_json::de::from_str(&resp.text()?)?;
-        Ok(data
-            .get("courses")
-            .unwrap()
-            .get(0)
-            .unwrap()
-            .get("assignments")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|assignment| {
-                (
-                    assignment
-                        .get("name")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_string(),
-                    assignment.get("id").unwrap().to_string(),
-                )
-            })
-            .collect::<HashMap<String, String>>())
+    /// Assignment name -> assignment
+    pub fn fetch_directory(&self) -> Result<HashMap<String, Assignment>> {
+        let data: AssignmentsResponse = self.client.call(
+            "mod_assign_get_assignments",
+            &[("courseids[0]", &self.course_id)],
+        )?;
+
+        let Some(course) = data.courses.into_iter().next() else {
+            return Err(KasmError::Validation(format!(
+                "course {} not found (or not accessible)",
+                self.course_id
+            )));
+        };
+
+        Ok(course
+            .assignments
+            .into_iter()
+            .map(|assignment| (assignment.name.clone(), assignment))
+            .collect())
     }
 
+    /// Group id -> submitted files
     pub fn get_submissions_list(
         &self,
-        assignment_id: &String,
-    ) -> Result<HashMap<String, Vec<Value>>, Box<dyn Error>> {
+        assignment_id: &str,
+    ) -> Result<HashMap<String, TeamSubmission>> {
         info!("getting submission list");
         warn!("this is going to take an eternity in big course pages");
         warn!("compare how slow moodle is when you click on 'view all submissions'");
         warn!("go brew a coffee or touch grass or something");
-        let resp = reqwest::blocking::Client::new()
-            .get(MOODLE_REST_URL)
-            .query(&[
-                ("moodlewsrestformat", "json"),
-                ("wsfunction", "mod_assign_get_submissions"),
-                ("wstoken", &self.token),
-                ("assignmentids[0]", assignment_id),
-            ])
-            .timeout(Duration::new(900, 0)) // 15 Min Timeout because Moodle is _fast_
-            .send()?;
-
-        let rt = &resp.text()?;
-        //let rt = include_str!("../target/submissions.json");
-        let parsed: Value = serde_json::from_str(rt)?;
-
-        let gid_plug_arrs: HashMap<String, Vec<Value>> = parsed
-            .get("assignments")
-            .unwrap()
-            .get(0)
-            .unwrap()
-            .get("submissions")
-            .unwrap()
-            .as_array()
-            .unwrap()
+        let data: SubmissionsResponse = self.client.call(
+            "mod_assign_get_submissions",
+            &[("assignmentids[0]", assignment_id)],
+        )?;
+
+        let mut gid_files: HashMap<String, TeamSubmission> = HashMap::new();
+        data.assignments
             .iter()
-            .filter_map(|submission| {
-                (submission.get("groupid")?, submission.get("plugins")?).into()
-            })
-            .map(|(a, b)| {
-                (
-                    a.to_string(),
-                    b.as_array()
-                        .unwrap()
-                        .iter()
-                        .filter_map(|plug| plug.get("fileareas")?.as_array())
-                        .flatten()
-                        .filter(|fa| fa.get("area").unwrap() == "submission_files")
-                        .filter_map(|fa| fa.get("files"))
-                        .filter_map(|ff| ff.as_array())
-                        .flatten()
-                        .cloned()
-                        .collect(),
-                )
-            })
-            .collect();
+            .flat_map(|assignment| &assignment.submissions)
+            .for_each(|submission| {
+                let team = gid_files.entry(submission.groupid.to_string()).or_default();
+                team.files.extend(submission.files().cloned());
+                if submission.status != "new" && submission.timemodified > 0 {
+                    let time = Moment::from_unix(submission.timemodified);
+                    team.submitted = team.submitted.max(Some(time));
+                }
+            });
 
-        Ok(gid_plug_arrs)
+        Ok(gid_files)
     }
 
-    pub fn get_group_mappings(
-        &self,
-        assignment_id: &String,
-    ) -> Result<(HashMap<String, String>, HashMap<String, Vec<String>>), Box<dyn Error>> {
+    pub fn get_group_mappings(&self, assignment_id: &str) -> Result<GroupMappings> {
         info!("fetching participants list");
-        let resp = reqwest::blocking::Client::new()
-            .get(MOODLE_REST_URL)
-            .query(&[
-                ("moodlewsrestformat", "json"),
-                ("wsfunction", "mod_assign_list_participants"),
-                ("wstoken", &self.token),
+        let participants: Vec<Participant> = self.client.call(
+            "mod_assign_list_participants",
+            &[
                 ("assignid", assignment_id),
                 ("groupid", "0"),
                 ("onlyids", "1"),
                 ("filter", ""),
-            ])
-            .timeout(Duration::new(900, 0))
-            .send()?;
-        let rt = resp.text()?;
-
-        //let rt = include_str!("../target/participants.json");
-        let parsed: Value = serde_json::from_str(&rt)?;
-        let mut group_members_mappings: HashMap<String, Vec<String>> = HashMap::new();
-
-        let mut groups: HashMap<String, String> = HashMap::new();
-        parsed
-            .as_array()
-            .unwrap()
+            ],
+        )?;
+
+        let mut mappings = GroupMappings::default();
+        participants
             .iter()
-            .filter_map(|part| {
-                (
-                    part.get("id")?,
-                    part.get("groupname")?,
-                    part.get("groupid")?,
-                    part.get("submissionstatus")?,
-                )
-                    .into()
-            })
-            .filter(|(_, _, _, status)| status.as_str().unwrap() == "submitted")
-            .for_each(|(userid, gname, gid, _)| {
-                group_members_mappings
+            .filter(|part| part.submissionstatus == "submitted")
+            .filter_map(|part| Some((part, part.groupid?, part.groupname.as_ref()?)))
+            .for_each(|(part, gid, gname)| {
+                mappings
+                    .members
                     .entry(gid.to_string())
-                    .or_insert_with(Vec::new)
-                    .push(userid.to_string());
-                groups
+                    .or_default()
+                    .push(part.id.to_string());
+                mappings
+                    .names
                     .entry(gid.to_string())
-                    .or_insert_with(|| gname.as_str().unwrap().into());
+                    .or_insert_with(|| gname.clone());
+                mappings.people.insert(
+                    part.id.to_string(),
+                    Member {
+                        name: part.fullname.clone(),
+                        participant_id: (part.recordid > 0).then(|| part.recordid.to_string()),
+                    },
+                );
             });
 
-        Ok((groups, group_members_mappings))
+        Ok(mappings)
     }
 
-    pub fn interactive_dl(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Fetches the assignment(s) selected by `cmd`. Asks which one
+    /// to fetch if nothing was selected.
+    pub fn fetch(&mut self, cmd: &FetchCmd) -> Result<()> {
         let assignments = self.fetch_directory()?;
-        let mut prompt_revord: Vec<&String> = assignments.keys().collect();
-        prompt_revord.sort_unstable();
-        prompt_revord.reverse();
-        let selected =
-            inquire::Select::new("Select an assignment to download", prompt_revord).prompt()?;
+        if cmd.list {
+            return list_assignments(&assignments, cmd.json);
+        }
+
+        let mut selected: Vec<&Assignment> = if let Some(assign_id) = cmd.assign_id {
+            let assignment = assignments
+                .values()
+                .find(|a| a.id == assign_id)
+                .ok_or_else(|| {
+                    KasmError::Validation(format!("there's no assignment with id {}", assign_id))
+                })?;
+            vec![assignment]
+        } else if let Some(ref sheet_id) = cmd.sheet_id {
+            let found: Vec<&Assignment> = assignments
+                .values()
+                .filter(|a| same_sheet(&sheet_id_of(&a.name), sheet_id))
+                .collect();
+            if found.len() > 1 {
+                return Err(KasmError::Validation(format!(
+                    "sheet {} is ambiguous ({}). use --assign-id or --match",
+                    sheet_id,
+                    found
+                        .iter()
+                        .map(|a| format!("{:?} = {}", a.name, a.id))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+            found
+        } else if let Some(ref pattern) = cmd.pattern {
+            let reg = regex::Regex::new(pattern)?;
+            assignments
+                .values()
+                .filter(|a| reg.is_match(&a.name))
+                .collect()
+        } else {
+            let mut prompt_revord: Vec<&String> = assignments.keys().collect();
+            prompt_revord.sort_unstable();
+            prompt_revord.reverse();
+            let selected =
+                inquire::Select::new("Select an assignment to download", prompt_revord).prompt()?;
+            vec![&assignments[selected]]
+        };
+
+        if selected.is_empty() {
+            return Err(KasmError::Validation(
+                "no assignment matches. try kasm fetch --list".into(),
+            ));
+        }
+
+        // Keep going if one of them fails, but tell about it in the end
+        selected.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut first_err = None;
+        for assignment in selected {
+            info!("fetching {:?}", assignment.name);
+            if let Err(e) = self.download_assignment(assignment) {
+                warn!("fetching {:?} failed: {}", assignment.name, e);
+                first_err.get_or_insert(e);
+            }
+        }
+
+        first_err.map_or(Ok(()), Err)
+    }
 
+    fn download_assignment(&mut self, assignment: &Assignment) -> Result<()> {
         let reg = regex::Regex::new(&self.config.groups_regex)?;
-        let dl_id = assignments.get(selected).unwrap();
+        let dl_id = &assignment.id.to_string();
 
         let participants = self.get_group_mappings(dl_id)?;
         let submissions = self.get_submissions_list(dl_id)?;
 
-        let nr_regex = regex::Regex::new(r"(\d?\d)")?;
-
-        let base_path = PathBuf::from(
-            UNPACK_PATH_FILENAME_BASE.to_string()
-                + nr_regex
-                    .captures(selected.as_str())
-                    .unwrap()
-                    .get(1)
-                    .unwrap()
-                    .into(),
-        );
+        let sheet_id = sheet_id_of(&assignment.name);
+        let base_path = self.config.templates.unpack_dir(&sheet_id)?;
         info!("Sel {:?}", base_path);
 
         let filtered_participants: HashMap<&String, &String> = participants
-            .0
+            .names
             .iter()
             .filter_map(|(k, v)| {
                 if reg.captures(v)?.get(1)?.as_str() == self.config.group {
@@ -290,178 +542,599 @@ impl MoodleFetcher {
             })
             .collect();
 
-        let sheet_id = selected.split(' ').last().unwrap().to_string();
-        let loc = format!("{}{}", UNPACK_PATH_FILENAME_BASE, sheet_id);
         let mut config = Grades {
-            location: loc.into(),
-            sheet_id: selected.split(' ').last().unwrap().to_string(),
+            location: base_path.clone(),
+            sheet_id: sheet_id.clone(),
+            max_grade: GradeValue::from_f64(assignment.grade).filter(|&max| max > GradeValue::ZERO),
+            tasks: Vec::new(),
+            due: (assignment.duedate > 0).then(|| Moment::from_unix(assignment.duedate)),
+            late_policy: self.config.late_policy.clone(),
             map: Default::default(),
             source: crate::config::Source::Autofetch,
             assign_id: Some(dl_id.to_owned()),
         };
-        self.gen_grading_files(&mut config, &filtered_participants, &participants.1)?;
+        self.gen_grading_files(
+            &mut config,
+            &filtered_participants,
+            &participants,
+            &submissions,
+        )?;
 
         let filtered_files: Vec<SubmissionFileMap> = filtered_participants
             .iter()
-            .filter(|(_, v)| reg.captures(v).unwrap().get(1).unwrap().as_str() == self.config.group)
             .flat_map(|(gid, gname)| {
                 let group_path = base_path.join(gname);
                 submissions
                     .get(gid.as_str())
-                    .unwrap()
-                    .iter()
-                    .filter_map(move |file| {
-                        SubmissionFileMap {
-                            dl_url: file.get("fileurl")?.as_str()?.to_string(),
-                            dl_path: group_path.join(file.get("filename")?.as_str()?),
-                            group_id: gid.to_string(),
-                            group_name: gname.to_string(),
-                        }
-                        .into()
+                    .into_iter()
+                    .flat_map(|team| &team.files)
+                    .map(move |file| SubmissionFileMap {
+                        dl_url: file.fileurl.clone(),
+                        dl_path: group_path.join(&file.filename),
+                        group_id: gid.to_string(),
+                        group_name: gname.to_string(),
+                        filesize: file.filesize,
+                        timemodified: file.timemodified,
                     })
             })
             .collect();
 
-        info!("downloading {} file(s)", filtered_files.len());
+        // Only download what's new or changed since the last fetch
+        let mut manifest = FetchManifest::read(&base_path)?;
+        let first_fetch = manifest.files.is_empty();
+        let (mut new, mut updated, mut unchanged) = (Vec::new(), Vec::new(), 0);
         for file in &filtered_files {
-            info!("downloading submission of {{{}}}", file.group_name);
-            std::fs::create_dir_all(file.dl_path.parent().unwrap())?;
-            let resp = reqwest::blocking::Client::new()
-                .get(&file.dl_url)
-                .query(&[("token", self.token.as_str())])
-                .timeout(Duration::new(900, 0))
-                .send()?;
-
-            std::fs::write(&file.dl_path, resp.bytes()?)?;
+            let rel = file
+                .dl_path
+                .strip_prefix(&base_path)
+                .unwrap_or(&file.dl_path);
+            match manifest.get(rel) {
+                Some(known) if !file.dl_path.is_file() => updated.push((file, known.clone())),
+                Some(known)
+                    if known.filesize == file.filesize
+                        && known.timemodified == file.timemodified =>
+                {
+                    unchanged += 1
+                }
+                Some(known) => updated.push((file, known.clone())),
+                // Never overwrite something the tutor put there
+                None if !first_fetch && file.dl_path.exists() => {
+                    warn!("{:?} exists but wasn't fetched by kasm, skipping", rel)
+                }
+                None => new.push(file),
+            }
+        }
+
+        let mut extracted = Extracted::read(&base_path)?;
+        let to_download: Vec<&SubmissionFileMap> = new
+            .iter()
+            .copied()
+            .chain(updated.iter().map(|(file, _)| *file))
+            .collect();
+        info!(
+            "downloading {} file(s) ({} unchanged)",
+            to_download.len(),
+            unchanged
+        );
+        let mut failed: Vec<(&SubmissionFileMap, KasmError)> = Vec::new();
+        self.download_all(&to_download, |file, result| {
+            if let Err(e) = result {
+                failed.push((file, e));
+                return Ok(());
+            }
+
+            // A changed archive has to be extracted again, its old
+            // extraction would be in the way
+            if self.config.recursive_unzip {
+                extracted.remove(&base_path, &file.dl_path)?;
+            }
+
+            // Written after every file, so an interrupted fetch can be resumed
+            manifest.record(FetchedFile {
+                path: file
+                    .dl_path
+                    .strip_prefix(&base_path)
+                    .unwrap_or(&file.dl_path)
+                    .to_path_buf(),
+                filesize: file.filesize,
+                timemodified: file.timemodified,
+            });
+            manifest.write(&base_path)
+        })?;
+        if self.config.recursive_unzip {
+            extracted.write(&base_path)?;
+        }
+
+        if !first_fetch {
+            for file in &new {
+                info!("new: {:?}", file.dl_path);
+            }
+            for (file, known) in &updated {
+                info!(
+                    "updated: {:?} ({} -> {} bytes)",
+                    file.dl_path, known.filesize, file.filesize
+                );
+            }
+            manifest
+                .files
+                .iter()
+                .filter(|known| {
+                    !filtered_files
+                        .iter()
+                        .any(|file| file.dl_path == base_path.join(&known.path))
+                })
+                .for_each(|known| {
+                    warn!("gone from moodle (kept locally): {:?}", known.path);
+                });
+            info!(
+                "since the last fetch: {} new, {} updated, {} unchanged file(s)",
+                new.len(),
+                updated.len(),
+                unchanged
+            );
+        }
+
+        if self.config.recursive_unzip {
+            crate::unpack::unzip_nested_all(&base_path)?;
+        }
+
+        self.config.hooks.run_post_unpack(&base_path, &config)?;
+
+        if !failed.is_empty() {
+            for (file, e) in &failed {
+                warn!("could not download {:?}: {}", file.dl_path, e);
+            }
+            return Err(KasmError::Incomplete(format!(
+                "{} file(s) could not be downloaded. run fetch again to retry",
+                failed.len()
+            )));
         }
 
         info!("done");
         Ok(())
     }
+
+    /// Downloads `files` on `download_jobs` threads sharing one client and
+    /// shows a progress bar. `on_done` gets called (on this thread) for
+    /// every file, failed or not.
+    fn download_all<'a>(
+        &self,
+        files: &[&'a SubmissionFileMap],
+        mut on_done: impl FnMut(&'a SubmissionFileMap, Result<()>) -> Result<()>,
+    ) -> Result<()> {
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let bar = ProgressBar::new(files.iter().map(|file| file.filesize).sum());
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{bar:40} {bytes}/{total_bytes} {msg} ({bytes_per_sec}, ETA {eta})",
+            )
+            .unwrap_or_else(|_| ProgressStyle::default_bar()),
+        );
+        bar.set_message(format!("0/{} files", files.len()));
+
+        let next = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel();
+        let result = std::thread::scope(|scope| {
+            for _ in 0..self.config.download_jobs().min(files.len()) {
+                let (tx, next, bar) = (tx.clone(), &next, &bar);
+                scope.spawn(move || {
+                    while let Some(&file) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let result = file
+                            .dl_path
+                            .parent()
+                            .map_or(Ok(()), std::fs::create_dir_all)
+                            .map_err(KasmError::io_at(&file.dl_path))
+                            .and_then(|_| {
+                                Ok(self
+                                    .client
+                                    .download_to(&file.dl_url, &file.dl_path, |n| bar.inc(n))?)
+                            });
+                        // The receiver is gone if something went wrong
+                        if tx.send((file, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(tx);
+
+            for (done, (file, result)) in rx.into_iter().enumerate() {
+                bar.set_message(format!("{}/{} files", done + 1, files.len()));
+                on_done(file, result)?;
+            }
+            Ok(())
+        });
+
+        bar.finish_and_clear();
+        result
+    }
     fn gen_grading_files(
         &self,
         conf: &mut Grades,
         groups: &HashMap<&String, &String>,
-        group_user_mappings: &HashMap<String, Vec<String>>,
-    ) -> Result<(), Box<dyn Error>> {
-        let mut grades_arr: Vec<Grade> = Vec::new();
-        let mut seen: Vec<String> = Vec::new();
-
-        groups.iter().for_each(|(&gid, &gname)| {
-            grades_arr.push(Grade {
-                grade: "".to_string(),
-                internal_id: Some(gid.to_owned()),
-                members: group_user_mappings.get(gid).cloned(),
-                target: gname.to_owned(),
-            });
-            seen.push(gid.to_owned());
-        });
+        mappings: &GroupMappings,
+        submissions: &HashMap<String, TeamSubmission>,
+    ) -> Result<()> {
+        let grades_toml_path = conf.location.join(UNPACK_GRADES_FILENAME);
 
-        info!("saw {} discreet groups", seen.len());
+        // Fetching again must not lose any grades
+        let mut grades_arr: Vec<Grade> = if grades_toml_path.is_file() {
+            let existing = Grades::read(grades_toml_path.clone())?;
+            conf.max_grade = existing.max_grade.or(conf.max_grade);
+            conf.tasks = existing.tasks;
+            conf.late_policy = existing.late_policy.or(conf.late_policy.take());
+            existing.map
+        } else {
+            Vec::new()
+        };
+        let known = grades_arr.len();
 
-        let grades_toml_path = conf.location.join(UNPACK_GRADES_FILENAME);
+        for (&gid, &gname) in groups {
+            let members = mappings.members.get(gid).cloned();
+            let participants: BTreeMap<String, Member> = members
+                .iter()
+                .flatten()
+                .filter_map(|userid| Some((userid.clone(), mappings.people.get(userid)?.clone())))
+                .collect();
+            let submitted = submissions.get(gid).and_then(|team| team.submitted);
+            match grades_arr
+                .iter_mut()
+                .find(|gd| gd.internal_id.as_ref() == Some(gid))
+            {
+                Some(gd) => {
+                    if gd.members != members {
+                        info!("members of {} changed", gname);
+                    }
+                    if gd.submitted.is_some() && gd.submitted != submitted {
+                        info!("{} submitted again", gname);
+                    }
+                    gd.members = members;
+                    gd.participants = participants;
+                    gd.target = gname.to_owned();
+                    gd.submitted = submitted;
+                }
+                None => grades_arr.push(Grade {
+                    grade: None,
+                    internal_id: Some(gid.to_owned()),
+                    members,
+                    participants,
+                    submitted,
+                    target: gname.to_owned(),
+                    ..Default::default()
+                }),
+            }
+        }
+
+        for gd in &grades_arr {
+            if gd.participant_id().is_none() {
+                warn!(
+                    "moodle didn't tell the participant ids of {}. repack can't pack its feedback",
+                    gd.target
+                );
+            }
+        }
+
+        info!("saw {} discreet groups", groups.len());
+        if known > 0 && grades_arr.len() > known {
+            info!(
+                "{} new group(s) since the last fetch",
+                grades_arr.len() - known
+            );
+        }
 
         info!("writing {:#?}", grades_toml_path);
         std::fs::create_dir_all(&conf.location)?;
-        std::fs::write(
-            grades_toml_path.clone(),
-            toml::to_string_pretty(&Grades {
-                location: grades_toml_path,
-                map: grades_arr,
-                sheet_id: conf.sheet_id.to_owned(),
-                source: conf.source.to_owned(),
-                assign_id: conf.assign_id.to_owned(),
-            })?,
-        )?;
-
-        Ok(())
+        conf.map = grades_arr;
+        Grades {
+            location: grades_toml_path,
+            map: conf.map.clone(),
+            sheet_id: conf.sheet_id.to_owned(),
+            max_grade: conf.max_grade,
+            tasks: conf.tasks.clone(),
+            due: conf.due,
+            late_policy: conf.late_policy.clone(),
+            source: conf.source.to_owned(),
+            assign_id: conf.assign_id.to_owned(),
+        }
+        .write()
     }
 
     fn set_grade_for(
         &self,
         assignid: String,
-        userid: String,
-        grade: String,
+        to: PushTo,
+        grade: Option<GradeValue>,
+        feedback: Option<String>,
+        files: &[PathBuf],
         dry_run: bool,
-    ) -> Result<(), Box<dyn Error>> {
-        if dry_run {
-            let userdata_req = reqwest::blocking::Client::new()
-                .get(MOODLE_REST_URL)
-                .query(&[
-                    ("moodlewsrestformat", "json"),
-                    ("wsfunction", "mod_assign_get_participant"),
-                    ("wstoken", self.token.as_str()),
-                    ("assignid", assignid.as_str()),
-                    ("userid", userid.as_str()),
-                ])
-                .timeout(Duration::new(900, 0))
-                .send()?;
-
-            let userdata: serde_json::Value = serde_json::from_reader(userdata_req)?;
-            let uname = userdata.get("fullname").unwrap().as_str().unwrap();
-            let gname = userdata.get("groupname").unwrap().as_str().unwrap();
-
-            info!("dry-run: would set {grade} for ({uname}) AND Group ({gname})");
+    ) -> Result<()> {
+        let (userid, applytoall) = match to {
+            PushTo::Team(userid) => (userid, "1"),
+            PushTo::Member(userid) => (userid, "0"),
+        };
+        let Some(grade) = grade else {
+            warn!("skipping {userid} because of empty grade");
             return Ok(());
-        }
+        };
 
-        if grade.is_empty() {
-            warn!("skipping {userid} because of empty grade");
+        if dry_run {
+            let userdata: ParticipantDetails = self.client.call(
+                "mod_assign_get_participant",
+                &[("assignid", assignid.as_str()), ("userid", userid)],
+            )?;
+            let uname = userdata.fullname;
+            match to {
+                PushTo::Team(_) => {
+                    let gname = userdata.groupname.unwrap_or_default();
+                    info!("dry-run: would set {grade} for ({uname}) AND Group ({gname})");
+                }
+                PushTo::Member(_) => info!("dry-run: would set {grade} for ({uname}) only"),
+            }
+            if let Some(feedback) = feedback {
+                info!("dry-run: with feedback\n{feedback}");
+            }
+            for file in files {
+                info!("dry-run: would upload {:?}", file);
+            }
             return Ok(());
         }
 
-        let adj_grade = grade.replace(',', ".");
+        let rest_grade = grade.to_rest();
+
+        let draft_itemid = if files.is_empty() {
+            None
+        } else {
+            info!("uploading {} feedback file(s) for {userid}", files.len());
+            Some(self.upload_draft_files(files)?)
+        };
 
         info!("Grading {userid} with {grade}");
-        let req = reqwest::blocking::Client::new()
-            .get(MOODLE_REST_URL)
-            .query(&[
-                ("moodlewsrestformat", "json"),
-                ("wsfunction", "mod_assign_save_grade"),
-                ("wstoken", self.token.as_str()),
-                // Moodle IDs
-                ("assignmentid", assignid.as_str()),
-                ("userid", userid.as_str()),
-                // Grade latest attempt
-                ("attemptnumber", "-1"),
-                // Set to graded
-                ("workflowstate", "graded"),
-                // Do not allow another attempt
-                ("addattempt", "0"),
-                // The grade itself
-                ("grade", adj_grade.as_str()),
-                // Apply to whole group
-                ("applytoall", "1"),
-                // Text Feedback (not implemented yet but Moodle needs it to be
-                // here)
-                ("plugindata[assignfeedbackcomments_editor][text]", ""),
-                ("plugindata[assignfeedbackcomments_editor][format]", "4"),
-            ])
-            .timeout(Duration::new(900, 0))
-            .send()?;
-
-        log::debug!("{:#?}", req.url());
-        log::debug!("{}", req.text()?);
+        let mut query = vec![
+            // Moodle IDs
+            ("assignmentid", assignid.as_str()),
+            ("userid", userid),
+            // Grade latest attempt
+            ("attemptnumber", "-1"),
+            // Set to graded
+            ("workflowstate", "graded"),
+            // Do not allow another attempt
+            ("addattempt", "0"),
+            // The grade itself
+            ("grade", rest_grade.as_str()),
+            // Apply to whole group (unless graded individually)
+            ("applytoall", applytoall),
+            // Text Feedback (Moodle needs it to be here, even if empty)
+            (
+                "plugindata[assignfeedbackcomments_editor][text]",
+                feedback.as_deref().unwrap_or(""),
+            ),
+            // FORMAT_HTML
+            ("plugindata[assignfeedbackcomments_editor][format]", "1"),
+        ];
+        // Feedback files (draft area)
+        if let Some(ref itemid) = draft_itemid {
+            query.push(("plugindata[files_filemanager]", itemid.as_str()));
+        }
+
+        // Moodle answers with null on success
+        self.client.call::<()>("mod_assign_save_grade", &query)?;
 
         Ok(())
     }
 
-    pub fn push_grades(&mut self, grades: &Grades, dry_run: bool) -> Result<(), Box<dyn Error>> {
-        let assign_id = grades
-            .assign_id
-            .clone()
-            .expect("Moodle Assignment ID in grades.toml");
-
-        grades.map.iter().for_each(|record| {
-            let members = record.members.clone().unwrap();
-            self.set_grade_for(
-                assign_id.to_owned(),
-                members.get(0).unwrap().to_owned(),
-                record.grade.to_owned(),
-                dry_run,
-            )
-            .expect("success setting grade");
-        });
+    /// Uploads `files` into a new draft area and returns its item id
+    fn upload_draft_files(&self, files: &[PathBuf]) -> Result<String> {
+        match self.client.upload_draft_files(files)?.first() {
+            Some(uploaded) => Ok(uploaded.itemid.to_string()),
+            None => Err(KasmError::Validation(
+                "moodle did not accept any of the feedback files".into(),
+            )),
+        }
+    }
+
+    pub fn push_grades(&mut self, grades: &Grades, dry_run: bool, with_files: bool) -> Result<()> {
+        let assign_id = grades.assign_id.clone().ok_or_else(|| {
+            KasmError::Config(format!(
+                "{:?} has no assign_id. push only works with fetched assignments",
+                grades.location
+            ))
+        })?;
+
+        // Same filter as `repack`
+        let filter = if with_files {
+            Some(RepackFilter::new(&self.config)?)
+        } else {
+            None
+        };
+
+        // Don't push half of the grades before noticing a typo
+        grades.validate(&self.config)?;
+
+        let mut pushed = grades.clone();
+        for (i, record) in grades.map.iter().enumerate() {
+            let team_dir = grades
+                .location
+                .parent()
+                .map(|unpack_path| unpack_path.join(&record.target));
+            let final_grade = grades.final_grade(record);
+            if let Some(note) = grades.late_note(record) {
+                info!("{}: {}", record.target, note);
+            }
+            let grade = final_grade.map(|g| g.to_csv());
+            let outcome = self.config.hooks.run(
+                HookPoint::PrePush,
+                &HookEnv {
+                    sheet_id: &grades.sheet_id,
+                    team_name: &record.target,
+                    team_dir: team_dir.as_deref().filter(|dir| dir.is_dir()),
+                    grade: grade.as_deref(),
+                    internal_id: record.internal_id.as_deref(),
+                },
+            )?;
+            if outcome == HookOutcome::Skip {
+                continue;
+            }
+
+            let files = match (&filter, &team_dir) {
+                (Some(filter), Some(dir)) if dir.is_dir() => filter.files(dir)?,
+                _ => Vec::new(),
+            };
+
+            let members = record.members.as_deref().unwrap_or_default();
+            let Some(first) = members.first() else {
+                warn!("{} has no members, skipping", record.target);
+                continue;
+            };
+            let feedback = grades.feedback_html_for(&record.target)?;
+            let mut pushed_members = BTreeMap::new();
+            if self.pushes_individually(record) {
+                info!("pushing the grades of {} per member", record.target);
+                for member in members {
+                    let grade = grades.final_grade_of(record, member);
+                    self.set_grade_for(
+                        assign_id.to_owned(),
+                        PushTo::Member(member),
+                        grade,
+                        feedback.clone(),
+                        &files,
+                        dry_run,
+                    )?;
+                    if let Some(grade) = grade {
+                        pushed_members.insert(member.clone(), grade);
+                    }
+                }
+            } else {
+                self.set_grade_for(
+                    assign_id.to_owned(),
+                    PushTo::Team(first),
+                    final_grade,
+                    feedback,
+                    &files,
+                    dry_run,
+                )?;
+            }
+
+            // Remember what we pushed (for `kasm status`)
+            if !dry_run && (final_grade.is_some() || !pushed_members.is_empty()) {
+                pushed.map[i].pushed = final_grade;
+                pushed.map[i].pushed_members = pushed_members;
+                pushed.write()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `record`'s members get their grades one by one (applytoall=0)
+    /// instead of Moodle handing the team's grade to everyone
+    fn pushes_individually(&self, record: &Grade) -> bool {
+        self.config.repack_structure == Structure::Individuals || !record.member_grades.is_empty()
+    }
+
+    /// User id -> grade currently on Moodle (latest attempt only)
+    fn get_current_grades(&self, assign_id: &str) -> Result<HashMap<String, GradeValue>> {
+        let data: GradesResponse = self
+            .client
+            .call("mod_assign_get_grades", &[("assignmentids[0]", assign_id)])?;
+
+        let mut latest: HashMap<u64, (i64, Option<GradeValue>)> = HashMap::new();
+        data.assignments
+            .iter()
+            .flat_map(|assignment| &assignment.grades)
+            .for_each(|ug| {
+                // Negative grades mean "not graded"
+                let grade = ug.grade.parse().ok();
+                let entry = latest.entry(ug.userid).or_insert((ug.attemptnumber, grade));
+                if ug.attemptnumber > entry.0 {
+                    *entry = (ug.attemptnumber, grade);
+                }
+            });
+
+        Ok(latest
+            .into_iter()
+            .filter_map(|(userid, (_, grade))| Some((userid.to_string(), grade?)))
+            .collect())
+    }
+
+    /// Shows how the local grades differ from what's on Moodle
+    pub fn diff(&self, grades: &Grades) -> Result<()> {
+        let assign_id = grades.assign_id.clone().ok_or_else(|| {
+            KasmError::Config(format!(
+                "{:?} has no assign_id. diff only works with fetched assignments",
+                grades.location
+            ))
+        })?;
+
+        info!("fetching current grades");
+        let remote = self.get_current_grades(&assign_id)?;
+
+        let header = ["team", "local", "moodle", "change"];
+        let mut rows: Vec<Vec<String>> = Vec::new();
+        let mut counts: HashMap<GradeChange, usize> = HashMap::new();
+        let mut add_row =
+            |target: String, local: Option<GradeValue>, remote: Option<GradeValue>| {
+                let change = GradeChange::of(local, remote);
+                *counts.entry(change).or_default() += 1;
+
+                let show =
+                    |g: Option<GradeValue>| g.map(|g| g.to_string()).unwrap_or_else(|| "-".into());
+                rows.push(vec![
+                    target,
+                    show(local),
+                    show(remote),
+                    change.label().into(),
+                ]);
+            };
+        for record in &grades.map {
+            let members = record.members.as_deref().unwrap_or_default();
+            if self.pushes_individually(record) {
+                for member in members {
+                    add_row(
+                        format!("{} ({})", record.target, member),
+                        grades.final_grade_of(record, member),
+                        remote.get(member).copied(),
+                    );
+                }
+                continue;
+            }
+
+            let mut member_grades: Vec<GradeValue> = members
+                .iter()
+                .filter_map(|member| remote.get(member).copied())
+                .collect();
+            member_grades.sort();
+            member_grades.dedup();
+            if member_grades.len() > 1 {
+                warn!(
+                    "members of {} have different grades on moodle ({}), comparing with the highest",
+                    record.target,
+                    member_grades
+                        .iter()
+                        .map(|g| g.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            add_row(
+                record.target.clone(),
+                grades.final_grade(record),
+                member_grades.last().copied(),
+            );
+        }
+
+        print_table(&header, &rows);
+        println!();
+        println!(
+            "sheet {}: {} added, {} changed, {} unchanged, {} removed",
+            grades.sheet_id,
+            counts.get(&GradeChange::Added).unwrap_or(&0),
+            counts.get(&GradeChange::Changed).unwrap_or(&0),
+            counts.get(&GradeChange::Unchanged).unwrap_or(&0),
+            counts.get(&GradeChange::Removed).unwrap_or(&0),
+        );
 
         Ok(())
     }
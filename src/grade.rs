@@ -1,57 +1,193 @@
-use std::error::Error;
-
 use crate::args::GradeCmd;
-use crate::config::{Grades, MasterCfg};
-use log::{error, info};
+use crate::config::{Grade, Grades, MasterCfg, Structure};
+use crate::error::{KasmError, Result};
+use crate::gradevalue::GradeValue;
+use crate::hooks::{HookEnv, HookPoint};
+use log::{info, warn};
+use regex::Regex;
+
+/// Checks whether the grade entry `gd` is addressed by `target`.
+///
+/// Groups are matched by the 2nd capture group of the master regex,
+/// individuals by their key (MatrNr. or participant id).
+fn is_target(master: &MasterCfg, reg: &Regex, gd: &Grade, target: &str) -> bool {
+    match master.grading_structure() {
+        Structure::Groups => reg
+            .captures(&gd.target)
+            .is_some_and(|caps| caps.get(2).is_some_and(|cap| cap.as_str() == target)),
+        Structure::Individuals => gd.target == target,
+    }
+}
+
+/// Tries to infer the target from the current working directory
+fn infer_target(master: &MasterCfg, reg: &Regex, grades: &Grades) -> Result<Option<String>> {
+    let cd = std::env::current_dir()?;
+    let mut components = cd.components().rev().filter_map(|c| c.as_os_str().to_str());
+
+    Ok(match master.grading_structure() {
+        Structure::Groups => components.find_map(|c| {
+            reg.captures(c)
+                .and_then(|caps| caps.get(2).map(|c| c.as_str().to_string()))
+        }),
+        Structure::Individuals => components
+            .find(|&c| grades.map.iter().any(|gd| gd.target == c))
+            .map(|c| c.to_string()),
+    })
+}
+
+/// Sorts out the positional arguments. When grading by task, there
+/// is no grade, so the first one is the target.
+fn grade_and_target(cfg: &GradeCmd) -> Result<(Option<GradeValue>, Option<String>)> {
+    match (cfg.tasks.is_empty(), &cfg.grade, &cfg.target) {
+        (true, Some(grade), target) => Ok((Some(grade.parse()?), target.clone())),
+        (false, target, None) => Ok((None, target.clone())),
+        (false, _, Some(_)) => Err(KasmError::Validation(
+            "give either a grade or --task, not both".into(),
+        )),
+        (true, None, _) => Err(KasmError::Validation("no grade given".into())),
+    }
+}
+
+/// Sets the points of the given tasks and sums them up
+fn grade_tasks(grades: &Grades, gd: &mut Grade, tasks: &[(String, GradeValue)]) -> Result<()> {
+    if grades.tasks.is_empty() {
+        return Err(KasmError::Validation(format!(
+            "no tasks defined in {:?}",
+            grades.location
+        )));
+    }
+
+    for (name, points) in tasks {
+        let Some(task) = grades.task(name) else {
+            return Err(KasmError::Validation(format!(
+                "unknown task {} (known: {})",
+                name,
+                grades
+                    .tasks
+                    .iter()
+                    .map(|t| t.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        };
+        if *points > task.max {
+            return Err(KasmError::Validation(format!(
+                "{} points exceed the maximum of {} for task {}",
+                points, task.max, name
+            )));
+        }
+        gd.tasks.insert(name.to_owned(), *points);
+    }
+
+    gd.grade = Some(gd.tasks.values().copied().sum());
+    Ok(())
+}
 
-pub fn grade(master: &MasterCfg, cfg: &GradeCmd, grades: &Grades) -> Result<(), Box<dyn Error>> {
+/// Overrides the grade of a single member of `gd`'s team
+fn grade_member(
+    master: &MasterCfg,
+    grades: &Grades,
+    gd: &mut Grade,
+    member: &str,
+    new_grade: Option<GradeValue>,
+) -> Result<GradeValue> {
+    if !gd.members.iter().flatten().any(|m| m == member) {
+        return Err(KasmError::Validation(format!(
+            "{} isn't a member of {} (members: {})",
+            member,
+            gd.target,
+            gd.members.as_deref().unwrap_or_default().join(", ")
+        )));
+    }
+
+    let grade = new_grade.unwrap_or_default();
+    grade.validate(grades.max_grade(master))?;
+    info!("grading {} of {} with {}", member, gd.target, grade);
+    gd.member_grades.insert(member.to_owned(), grade);
+    Ok(grade)
+}
+
+pub fn grade(master: &MasterCfg, cfg: &GradeCmd, grades: &Grades) -> Result<()> {
     let reg = regex::Regex::new(&master.groups_regex)?;
+    let (new_grade, target) = grade_and_target(cfg)?;
 
-    let target = match &cfg.target {
-        Some(str) => str.clone(),
+    let target = match target {
+        Some(str) => str,
         None => {
-            let cd = std::env::current_dir()?;
-            let infer = cd.components().rev().find_map(|c| {
-                reg.captures(c.as_os_str().to_str().unwrap())
-                    .and_then(|caps| caps.get(2).map(|c| c.as_str()))
-            });
-
-            if let Some(infer) = infer {
-                info!("inferred group {} based on path", infer);
-                infer.to_string()
+            if let Some(infer) = infer_target(master, &reg, grades)? {
+                info!("inferred target {} based on path", infer);
+                infer
             } else {
-                error!("you didn't specify the group to be graded and it couldn't be inferred");
-                return Err("".into());
+                return Err(KasmError::Validation(
+                    "you didn't specify the target to be graded and it couldn't be inferred".into(),
+                ));
             }
         }
     };
 
-    info!("grading {} with {}", target, cfg.grade);
-
-    let mut grades = grades.clone();
-    let mut changed = false;
-    grades
+    let mut new_grades = grades.clone();
+    let Some(gd) = new_grades
         .map
         .iter_mut()
-        .find(|gd| {
-            reg.captures(&gd.target).map_or(false, |caps| {
-                caps.get(2).map_or(false, |cap| cap.as_str() == target)
-            })
-        })
-        .map(|gd| {
-            info!("found match");
-            changed = true;
-            gd.grade = cfg.grade.to_owned();
-            Some(())
-        })
-        .or_else(|| {
-            error!("no matching group found!");
-            None
-        });
-
-    if changed {
-        info!("writing grades");
-        std::fs::write(grades.location.clone(), toml::to_string_pretty(&grades)?)?;
+        .find(|gd| is_target(master, &reg, gd, &target))
+    else {
+        return Err(KasmError::Validation(format!(
+            "no matching target found for {}!",
+            target
+        )));
+    };
+
+    info!("found match");
+    let (grade, final_grade) = if let Some(ref member) = cfg.member {
+        let grade = grade_member(master, grades, gd, member, new_grade)?;
+        (grade, grades.final_grade_of(gd, member))
+    } else {
+        match new_grade {
+            Some(new_grade) => {
+                if !gd.tasks.is_empty() {
+                    warn!("overriding the sum of the task points of {}", target);
+                }
+                gd.grade = Some(new_grade);
+            }
+            None => grade_tasks(grades, gd, &cfg.tasks)?,
+        }
+
+        let grade = gd.grade.unwrap_or_default();
+        info!("grading {} with {}", target, grade);
+        grade.validate(grades.max_grade(master))?;
+        (grade, grades.final_grade(gd))
+    };
+
+    if let Some(note) = grades.late_note(gd) {
+        warn!("{} {}", target, note);
+        if let Some(final_grade) = final_grade.filter(|&g| g != grade) {
+            warn!("{} will be published instead", final_grade);
+        }
     }
+
+    if let Some(ref feedback) = cfg.feedback {
+        gd.feedback = Some(feedback.to_owned());
+    }
+    let gd = gd.clone();
+    let grades = new_grades;
+    let grade = grade.to_csv();
+
+    info!("writing grades");
+    grades.write()?;
+    let team_dir = grades
+        .location
+        .parent()
+        .map(|unpack_path| unpack_path.join(&gd.target));
+    master.hooks.run(
+        HookPoint::PostGrade,
+        &HookEnv {
+            sheet_id: &grades.sheet_id,
+            team_name: &gd.target,
+            team_dir: team_dir.as_deref().filter(|dir| dir.is_dir()),
+            grade: Some(&grade),
+            internal_id: gd.internal_id.as_deref(),
+        },
+    )?;
+
     Ok(())
 }
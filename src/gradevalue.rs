@@ -0,0 +1,236 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::KasmError;
+
+/// Decimal places Moodle stores grades with
+const DECIMALS: u32 = 5;
+const SCALE: i64 = 10i64.pow(DECIMALS);
+
+/// A (non-negative) grade. Stored as a fixed point number, so that
+/// adding up points never produces floating point noise.
+///
+/// Parses both "10,5" and "10.5". Moodle's grading worksheet wants a
+/// decimal comma, its REST API a decimal point.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GradeValue(i64);
+
+impl GradeValue {
+    pub const ZERO: GradeValue = GradeValue(0);
+
+    /// Converts a number Moodle gave us. Negative grades (which
+    /// Moodle uses for scales) and NaN yield None.
+    pub fn from_f64(value: f64) -> Option<GradeValue> {
+        (value.is_finite() && value >= 0.0)
+            .then(|| GradeValue((value * SCALE as f64).round() as i64))
+    }
+
+    /// Parses an optional grade. Empty strings mean "no grade".
+    pub fn parse_opt(s: &str) -> Result<Option<GradeValue>, KasmError> {
+        if s.trim().is_empty() {
+            Ok(None)
+        } else {
+            s.parse().map(Some)
+        }
+    }
+
+    fn format(&self, separator: char) -> String {
+        let int = self.0 / SCALE;
+        let frac = format!("{:0width$}", self.0 % SCALE, width = DECIMALS as usize);
+        // At least two decimals, like Moodle does
+        let frac = frac.trim_end_matches('0');
+        format!("{}{}{:0<2}", int, separator, frac)
+    }
+
+    /// Format of the grading worksheet, e.g. "10,50"
+    pub fn to_csv(&self) -> String {
+        self.format(',')
+    }
+
+    /// Format of Moodle's REST API, e.g. "10.50"
+    pub fn to_rest(&self) -> String {
+        self.format('.')
+    }
+
+    /// Takes `percent` percent off (rounded to two decimals)
+    pub fn minus_percent(&self, percent: u32) -> GradeValue {
+        let kept = 100 - i64::from(percent.min(100));
+        let cents = (self.0 * kept / 100 + SCALE / 200) / (SCALE / 100);
+        GradeValue(cents * (SCALE / 100))
+    }
+
+    /// Fails if the grade is greater than `max` (if there is one)
+    pub fn validate(&self, max: Option<GradeValue>) -> Result<(), KasmError> {
+        match max {
+            Some(max) if *self > max => Err(KasmError::Validation(format!(
+                "grade {} exceeds the maximum grade of {}",
+                self, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for GradeValue {
+    type Err = KasmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            KasmError::Validation(format!(
+                "{:?} is not a valid grade (expected e.g. 10 or 10,5)",
+                s
+            ))
+        };
+
+        let (int, frac) = s.trim().split_once([',', '.']).unwrap_or((s.trim(), ""));
+        if int.is_empty()
+            || !int.chars().all(|c| c.is_ascii_digit())
+            || !frac.chars().all(|c| c.is_ascii_digit())
+            || frac.len() > DECIMALS as usize
+        {
+            return Err(invalid());
+        }
+
+        let int: i64 = int.parse().map_err(|_| invalid())?;
+        let frac: i64 = format!("{:0<width$}", frac, width = DECIMALS as usize)
+            .parse()
+            .map_err(|_| invalid())?;
+
+        int.checked_mul(SCALE)
+            .and_then(|int| int.checked_add(frac))
+            .map(GradeValue)
+            .ok_or_else(invalid)
+    }
+}
+
+impl Display for GradeValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_csv())
+    }
+}
+
+impl std::ops::Add for GradeValue {
+    type Output = GradeValue;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        GradeValue(self.0 + rhs.0)
+    }
+}
+
+impl std::iter::Sum for GradeValue {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(GradeValue::ZERO, |acc, g| acc + g)
+    }
+}
+
+impl Serialize for GradeValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_csv())
+    }
+}
+
+impl<'de> Deserialize<'de> for GradeValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// (De)serializes an optional grade as a string, "" meaning no grade.
+/// Keeps grades.toml files of older versions readable.
+pub mod optional {
+    use super::GradeValue;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<GradeValue>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.serialize_str(&value.to_csv()),
+            None => serializer.serialize_str(""),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<GradeValue>, D::Error> {
+        GradeValue::parse_opt(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grade(s: &str) -> GradeValue {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn rejects_invalid_grades() {
+        let invalid = [
+            "1O,5",
+            "",
+            " ",
+            ",5",
+            "-1",
+            "1,234567",
+            "1,2,3",
+            "10 5",
+            "100000000000000",
+            "99999999999999999999",
+        ];
+        for s in invalid {
+            assert!(
+                s.parse::<GradeValue>().is_err(),
+                "{:?} should be invalid",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn comma_and_point_are_the_same() {
+        assert_eq!(grade("10,5"), grade("10.5"));
+        assert_eq!(grade("10,5"), grade("10,50000"));
+        assert_eq!(grade(" 10 "), grade("10,"));
+        assert_eq!(GradeValue::parse_opt("").unwrap(), None);
+        assert_eq!(GradeValue::parse_opt("3").unwrap(), Some(grade("3,0")));
+    }
+
+    #[test]
+    fn formats_like_moodle() {
+        assert_eq!(grade("10,5").to_csv(), "10,50");
+        assert_eq!(grade("10,5").to_rest(), "10.50");
+        assert_eq!(GradeValue::ZERO.to_csv(), "0,00");
+        assert_eq!(grade("0,12345").to_csv(), "0,12345");
+        assert_eq!(grade("7.125").to_rest(), "7.125");
+        assert_eq!(
+            [grade("0,1"), grade("0,2")].into_iter().sum::<GradeValue>(),
+            grade("0,3")
+        );
+    }
+
+    #[test]
+    fn minus_percent_rounds_to_two_decimals() {
+        assert_eq!(grade("10").minus_percent(0), grade("10"));
+        assert_eq!(grade("10").minus_percent(33), grade("6,7"));
+        assert_eq!(grade("7,77").minus_percent(10), grade("6,99"));
+        // Rounds half up
+        assert_eq!(grade("0,05").minus_percent(50), grade("0,03"));
+        assert_eq!(grade("0,12345").minus_percent(0), grade("0,12"));
+        assert_eq!(grade("10").minus_percent(100), GradeValue::ZERO);
+        assert_eq!(grade("10").minus_percent(150), GradeValue::ZERO);
+    }
+
+    #[test]
+    fn validates_against_the_maximum() {
+        let max = Some(grade("10"));
+        grade("10,00").validate(max).unwrap();
+        assert!(grade("10,00001").validate(max).is_err());
+        grade("1000").validate(None).unwrap();
+    }
+}
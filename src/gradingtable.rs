@@ -1,63 +1,472 @@
-use log::trace;
-use std::{error::Error, path::PathBuf};
+use log::{debug, trace, warn};
+use std::{fs::File, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
+use strum::{EnumIter, IntoEnumIterator};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::error::{KasmError, Result};
+
+/// Columns of Moodle's grading worksheet that we care about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
+pub enum Column {
+    InternalId,
+    Name,
+    UniId,
+    Status,
+    Group,
+    Grade,
+    BestGrade,
+    GradeLocked,
+    LastChangeSubmission,
+    LastChangeGrade,
+    FeedbackComment,
+}
+
+/// Header names of the grading worksheet. They depend on the language of
+/// Moodle's UI at the time of the export. A custom mapping can be supplied
+/// in the master config (`[csv_headers]`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CsvHeaders {
+    pub internal_id: String,
+    pub name: String,
+    pub uni_id: String,
+    pub status: String,
+    pub group: String,
+    pub grade: String,
+    pub best_grade: String,
+    pub grade_locked: String,
+    pub last_change_submission: String,
+    pub last_change_grade: String,
+    pub feedback_comment: String,
+}
+
+impl Default for CsvHeaders {
+    fn default() -> Self {
+        Self::german()
+    }
+}
+
+impl CsvHeaders {
+    pub fn german() -> CsvHeaders {
+        CsvHeaders {
+            internal_id: "ID".into(),
+            name: "Vollständiger Name".into(),
+            uni_id: "Matrikelnummer".into(),
+            status: "Status".into(),
+            group: "Gruppe".into(),
+            grade: "Bewertung".into(),
+            best_grade: "Bestwertung".into(),
+            grade_locked: "Bewertung kann geändert werden".into(),
+            last_change_submission: "Zuletzt geändert (Abgabe)".into(),
+            last_change_grade: "Zuletzt geändert (Bewertung)".into(),
+            feedback_comment: "Feedback als Kommentar".into(),
+        }
+    }
+
+    pub fn english() -> CsvHeaders {
+        CsvHeaders {
+            internal_id: "Identifier".into(),
+            name: "Full name".into(),
+            uni_id: "ID number".into(),
+            status: "Status".into(),
+            group: "Group".into(),
+            grade: "Grade".into(),
+            best_grade: "Maximum grade".into(),
+            grade_locked: "Grade can be changed".into(),
+            last_change_submission: "Last modified (submission)".into(),
+            last_change_grade: "Last modified (grade)".into(),
+            feedback_comment: "Feedback comments".into(),
+        }
+    }
+
+    pub fn name(&self, col: Column) -> &str {
+        match col {
+            Column::InternalId => &self.internal_id,
+            Column::Name => &self.name,
+            Column::UniId => &self.uni_id,
+            Column::Status => &self.status,
+            Column::Group => &self.group,
+            Column::Grade => &self.grade,
+            Column::BestGrade => &self.best_grade,
+            Column::GradeLocked => &self.grade_locked,
+            Column::LastChangeSubmission => &self.last_change_submission,
+            Column::LastChangeGrade => &self.last_change_grade,
+            Column::FeedbackComment => &self.feedback_comment,
+        }
+    }
+
+    /// Maps each header of the csv to one of our columns (if it is one),
+    /// ignoring case. Returns None if the columns we can't live without
+    /// are missing.
+    fn match_headers(&self, headers: &[String]) -> Option<Vec<Option<Column>>> {
+        let mapped: Vec<Option<Column>> = headers
+            .iter()
+            .map(|h| {
+                let h = h.trim().to_lowercase();
+                Column::iter().find(|&col| self.name(col).to_lowercase() == h)
+            })
+            .collect();
+
+        [Column::InternalId, Column::Name, Column::Grade]
+            .iter()
+            .all(|req| mapped.contains(&Some(*req)))
+            .then_some(mapped)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct GradingRecord {
     /// Internal moodle id
-    #[serde(rename = "ID")]
     pub internal_id: String,
 
     /// Full name
-    #[serde(rename = "Vollständiger Name")]
     pub name: String,
 
     /// University ID Number (MatrNr.)
-    #[serde(rename = "Matrikelnummer")]
     pub uni_id: String,
 
-    #[serde(rename = "Status")]
     pub status: String,
 
     /// Full group name
-    #[serde(rename = "Gruppe")]
     pub group: String,
 
     // Other fields
-    #[serde(rename = "Bewertung")]
     pub grade: String,
 
-    #[serde(rename = "Bestwertung")]
     pub best_grade: String,
 
-    #[serde(rename = "Bewertung kann geändert werden")]
     pub grade_locked: String,
 
-    #[serde(rename = "Zuletzt geändert (Abgabe)")]
     pub last_change_submission: String,
 
-    #[serde(rename = "Zuletzt geändert (Bewertung)")]
     pub last_change_grade: String,
 
-    #[serde(rename = "Feedback als Kommentar")]
     pub feedback_comment: String,
+
+    /// The row as it was read, including the columns we don't know
+    pub raw: Vec<String>,
 }
 
 impl GradingRecord {
-    pub fn from_csv(path: &PathBuf) -> Result<Vec<GradingRecord>, Box<dyn Error>> {
+    /// Numeric participant id, i.e. the internal id without its
+    /// (localized) "Teilnehmer/in" prefix
+    pub fn participant_id(&self) -> &str {
+        self.internal_id
+            .trim_start_matches(|c: char| !c.is_ascii_digit())
+            .trim()
+    }
+
+    /// Key we use for the student's directory and grade entry.
+    /// Falls back to the participant id if the MatrNr. is hidden.
+    pub fn student_key(&self) -> &str {
+        if self.uni_id.trim().is_empty() {
+            self.participant_id()
+        } else {
+            self.uni_id.trim()
+        }
+    }
+
+    pub fn get(&self, col: Column) -> &str {
+        match col {
+            Column::InternalId => &self.internal_id,
+            Column::Name => &self.name,
+            Column::UniId => &self.uni_id,
+            Column::Status => &self.status,
+            Column::Group => &self.group,
+            Column::Grade => &self.grade,
+            Column::BestGrade => &self.best_grade,
+            Column::GradeLocked => &self.grade_locked,
+            Column::LastChangeSubmission => &self.last_change_submission,
+            Column::LastChangeGrade => &self.last_change_grade,
+            Column::FeedbackComment => &self.feedback_comment,
+        }
+    }
+
+    fn get_mut(&mut self, col: Column) -> &mut String {
+        match col {
+            Column::InternalId => &mut self.internal_id,
+            Column::Name => &mut self.name,
+            Column::UniId => &mut self.uni_id,
+            Column::Status => &mut self.status,
+            Column::Group => &mut self.group,
+            Column::Grade => &mut self.grade,
+            Column::BestGrade => &mut self.best_grade,
+            Column::GradeLocked => &mut self.grade_locked,
+            Column::LastChangeSubmission => &mut self.last_change_submission,
+            Column::LastChangeGrade => &mut self.last_change_grade,
+            Column::FeedbackComment => &mut self.feedback_comment,
+        }
+    }
+}
+
+/// A parsed grading worksheet. Remembers the headers it was read with, so
+/// that it can be written back in the same language.
+#[derive(Debug, Clone, Default)]
+pub struct GradingTable {
+    pub headers: CsvHeaders,
+
+    /// The header row as it was read (without a BOM)
+    pub raw_headers: Vec<String>,
+
+    /// Our column for each column of the csv (None if we don't know it)
+    pub columns: Vec<Option<Column>>,
+
+    pub records: Vec<GradingRecord>,
+}
+
+impl GradingTable {
+    /// Parses a grading worksheet. The header language is detected
+    /// automatically; `custom` headers (if any) are tried first.
+    pub fn from_csv(path: &PathBuf, custom: Option<&CsvHeaders>) -> Result<GradingTable> {
         if !path.is_file() {
-            return Err("could not find csv".into());
+            return Err(KasmError::Validation(format!(
+                "could not find csv {:?}",
+                path
+            )));
         }
 
         let file = std::fs::File::open(path)?;
         let mut reader = csv::Reader::from_reader(file);
-        Ok(reader
-            .deserialize()
+        // Strip a possible UTF-8 BOM
+        let raw_headers: Vec<String> = reader
+            .headers()?
+            .iter()
+            .map(|h| h.trim_start_matches('\u{feff}').to_string())
+            .collect();
+
+        let (headers, mapped) = custom
+            .into_iter()
+            .cloned()
+            .chain([CsvHeaders::german(), CsvHeaders::english()])
+            .find_map(|candidate| {
+                let mapped = candidate.match_headers(&raw_headers)?;
+                Some((candidate, mapped))
+            })
+            .ok_or_else(|| {
+                KasmError::Validation(format!("unknown csv header {:?}", raw_headers))
+            })?;
+
+        debug!("csv headers detected as {:?}", headers);
+
+        let records = reader
+            .records()
             .filter_map(|result| {
                 trace!("checking {:?}", result);
-                result.ok()
+                let raw = result.ok()?;
+                let mut record = GradingRecord {
+                    raw: raw.iter().map(String::from).collect(),
+                    ..Default::default()
+                };
+                mapped.iter().zip(raw.iter()).for_each(|(col, val)| {
+                    if let Some(col) = col {
+                        *record.get_mut(*col) = val.to_string();
+                    }
+                });
+                Some(record)
             })
-            .collect::<Vec<GradingRecord>>())
+            .collect::<Vec<GradingRecord>>();
+
+        Ok(GradingTable {
+            headers,
+            raw_headers,
+            columns: mapped,
+            records,
+        })
+    }
+
+    /// Creates a csv at `path` with the same headers as this table.
+    /// Columns we don't know are written back as they were read.
+    pub fn writer(&self, path: &PathBuf) -> Result<GradingTableWriter> {
+        let mut inner = csv::WriterBuilder::new()
+            .delimiter(b',')
+            .quote_style(csv::QuoteStyle::Always)
+            .from_path(path)?;
+
+        inner.write_record(&self.raw_headers)?;
+
+        Ok(GradingTableWriter {
+            inner,
+            columns: self.columns.clone(),
+            dropped_feedback: false,
+        })
+    }
+}
+
+/// Writes grading records using the columns of the table it was created from
+pub struct GradingTableWriter {
+    inner: csv::Writer<File>,
+    columns: Vec<Option<Column>>,
+    /// Whether we already warned about feedback without a column for it
+    dropped_feedback: bool,
+}
+
+impl GradingTableWriter {
+    pub fn serialize(&mut self, record: &GradingRecord) -> Result<()> {
+        // Moodle only exports the column if feedback comments are enabled
+        if !record.feedback_comment.is_empty()
+            && !self.dropped_feedback
+            && !self.columns.contains(&Some(Column::FeedbackComment))
+        {
+            warn!("no feedback comment column in the grading worksheet, leaving textual feedback out of the csv");
+            self.dropped_feedback = true;
+        }
+
+        self.inner
+            .write_record(self.columns.iter().enumerate().map(|(i, col)| match col {
+                Some(col) => record.get(*col),
+                None => record.raw.get(i).map_or("", String::as_str),
+            }))?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GERMAN: &str = "\"ID\",\"Vollständiger Name\",\"Matrikelnummer\",\"E-Mail-Adresse\",\"Status\",\"Gruppe\",\"Bewertung\",\"Bestwertung\",\"Bewertung kann geändert werden\",\"Zuletzt geändert (Abgabe)\",\"Zuletzt geändert (Bewertung)\",\"Feedback als Kommentar\"
+\"Teilnehmer/in12345\",\"Max Mustermann\",\"123456\",\"max@example.org\",\"Zur Bewertung abgegeben\",\"Übungsgruppe 01 -- Abgabeteam 07\",\"\",\"10,00\",\"Ja\",\"Montag, 1. Mai 2023, 12:00\",\"-\",\"\"
+";
+
+    const ENGLISH: &str = "\"Identifier\",\"Full name\",\"ID number\",\"Email address\",\"Status\",\"Group\",\"Grade\",\"Maximum grade\",\"Grade can be changed\",\"Last modified (submission)\",\"Last modified (grade)\",\"Feedback comments\"
+\"Participant 12345\",\"Max Mustermann\",\"\",\"max@example.org\",\"Submitted for grading\",\"Übungsgruppe 01 -- Abgabeteam 07\",\"\",\"10.00\",\"Yes\",\"Monday, 1 May 2023, 12:00 PM\",\"-\",\"\"
+";
+
+    /// Fewer columns, in a different order, and one we don't know
+    const CUSTOM: &str = "\"Bewertung\",\"Kennung\",\"Notiz\",\"Name\"
+\"\",\"Participant 12345\",\"spät\",\"Max Mustermann\"
+";
+
+    fn custom_headers() -> CsvHeaders {
+        CsvHeaders {
+            internal_id: "Kennung".into(),
+            name: "Name".into(),
+            ..CsvHeaders::german()
+        }
+    }
+
+    /// Parses `csv`, writes it back with a grade and returns the table and
+    /// the written csv
+    fn round_trip(csv: &str, custom: Option<&CsvHeaders>) -> (GradingTable, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.csv");
+        let output = dir.path().join("out.csv");
+        std::fs::write(&input, csv).unwrap();
+
+        let table = GradingTable::from_csv(&input, custom).unwrap();
+        let mut record = table.records[0].clone();
+        record.grade = "7,50".into();
+
+        let mut writer = table.writer(&output).unwrap();
+        writer.serialize(&record).unwrap();
+        writer.flush().unwrap();
+
+        (table, std::fs::read_to_string(output).unwrap())
+    }
+
+    fn header_line(csv: &str) -> &str {
+        csv.lines().next().unwrap().trim_start_matches('\u{feff}')
+    }
+
+    /// The data row of `csv` with the (empty) grade set to 7,50
+    fn graded_row(csv: &str) -> String {
+        csv.lines()
+            .nth(1)
+            .unwrap()
+            .replacen("Abgabeteam 07\",\"\"", "Abgabeteam 07\",\"7,50\"", 1)
+    }
+
+    #[test]
+    fn german_export_round_trips() {
+        let (table, written) = round_trip(GERMAN, None);
+        assert_eq!(table.headers, CsvHeaders::german());
+        assert_eq!(table.columns.len(), 12);
+        assert_eq!(table.columns[3], None);
+        assert_eq!(table.records.len(), 1);
+        assert_eq!(table.records[0].participant_id(), "12345");
+        assert_eq!(table.records[0].student_key(), "123456");
+        assert_eq!(header_line(&written), header_line(GERMAN));
+        // The email address isn't ours, but it's kept
+        assert_eq!(written.lines().nth(1).unwrap(), graded_row(GERMAN));
+    }
+
+    #[test]
+    fn english_export_round_trips() {
+        let (table, written) = round_trip(ENGLISH, None);
+        assert_eq!(table.headers, CsvHeaders::english());
+        assert_eq!(table.records[0].best_grade, "10.00");
+        // No MatrNr., so the participant id is used instead
+        assert_eq!(table.records[0].student_key(), "12345");
+        assert_eq!(header_line(&written), header_line(ENGLISH));
+        assert_eq!(written.lines().nth(1).unwrap(), graded_row(ENGLISH));
+    }
+
+    #[test]
+    fn headers_match_ignoring_case() {
+        let shouting = ENGLISH.replacen("Maximum grade", "MAXIMUM GRADE", 1);
+        let (table, written) = round_trip(&shouting, None);
+        assert_eq!(table.columns[7], Some(Column::BestGrade));
+        assert_eq!(table.records[0].best_grade, "10.00");
+        // Written back as it was read
+        assert_eq!(header_line(&written), header_line(&shouting));
+    }
+
+    #[test]
+    fn custom_headers_round_trip() {
+        let custom = custom_headers();
+        let (table, written) = round_trip(CUSTOM, Some(&custom));
+        assert_eq!(table.headers, custom);
+        assert_eq!(
+            table.columns,
+            vec![
+                Some(Column::Grade),
+                Some(Column::InternalId),
+                None,
+                Some(Column::Name)
+            ]
+        );
+        assert_eq!(table.records[0].name, "Max Mustermann");
+        assert_eq!(header_line(&written), header_line(CUSTOM));
+        assert_eq!(
+            written.lines().nth(1),
+            Some("\"7,50\",\"Participant 12345\",\"spät\",\"Max Mustermann\"")
+        );
+
+        // Without the mapping the headers are unknown
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.csv");
+        std::fs::write(&input, CUSTOM).unwrap();
+        assert!(GradingTable::from_csv(&input, None).is_err());
+    }
+
+    #[test]
+    fn feedback_without_column_is_noticed() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in.csv");
+        std::fs::write(&input, CUSTOM).unwrap();
+        let table = GradingTable::from_csv(&input, Some(&custom_headers())).unwrap();
+
+        let mut writer = table.writer(&dir.path().join("out.csv")).unwrap();
+        writer.serialize(&table.records[0]).unwrap();
+        assert!(!writer.dropped_feedback);
+
+        let mut record = table.records[0].clone();
+        record.feedback_comment = "gut".into();
+        writer.serialize(&record).unwrap();
+        assert!(writer.dropped_feedback);
+    }
+
+    #[test]
+    fn bom_is_detected_and_stripped() {
+        let (table, written) = round_trip(&format!("\u{feff}{}", GERMAN), None);
+        assert_eq!(table.headers, CsvHeaders::german());
+        assert_eq!(table.columns[0], Some(Column::InternalId));
+        assert_eq!(table.records[0].participant_id(), "12345");
+        assert_eq!(written.lines().next(), GERMAN.lines().next());
     }
 }
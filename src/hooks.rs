@@ -0,0 +1,172 @@
+use std::{path::Path, process::Command};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+use crate::config::Grades;
+use crate::error::{KasmError, Result};
+
+/// Runs `cmd` through the system's shell
+pub fn shell(cmd: &str) -> Command {
+    if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(cmd);
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(cmd);
+        c
+    }
+}
+
+/// What happens when a hook exits with a non-zero status
+#[derive(Clone, Debug, Default, Display, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HookFailure {
+    /// Abort the whole command
+    #[default]
+    Abort,
+    /// Skip the team the hook ran for and carry on. Only `pre_*` hooks
+    /// can skip something; a failed `post_*` hook just gets reported.
+    Skip,
+}
+
+/// Shell commands to run at certain points of kasm's lifecycle.
+/// They run inside the team's directory (if there is one) and get
+/// the details of the team passed as `KASM_*` environment variables.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Hooks {
+    /// Runs once per team directory after `unpack` and `fetch`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_unpack: Option<String>,
+
+    /// Runs once per team directory before it gets repacked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_repack: Option<String>,
+
+    /// Runs after `grade` saved a team's grade
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_grade: Option<String>,
+
+    /// Runs once per team before its grade gets pushed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_push: Option<String>,
+
+    /// What to do if a hook fails
+    #[serde(default)]
+    pub on_failure: HookFailure,
+}
+
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum HookPoint {
+    PostUnpack,
+    PreRepack,
+    PostGrade,
+    PrePush,
+}
+
+/// Whether the caller should go on with the team the hook ran for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookOutcome {
+    Continue,
+    Skip,
+}
+
+/// Details about the team a hook runs for
+#[derive(Clone, Debug, Default)]
+pub struct HookEnv<'a> {
+    pub sheet_id: &'a str,
+    pub team_name: &'a str,
+    pub team_dir: Option<&'a Path>,
+    pub grade: Option<&'a str>,
+    pub internal_id: Option<&'a str>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.post_unpack.is_none()
+            && self.pre_repack.is_none()
+            && self.post_grade.is_none()
+            && self.pre_push.is_none()
+    }
+
+    fn command(&self, point: HookPoint) -> Option<&String> {
+        match point {
+            HookPoint::PostUnpack => self.post_unpack.as_ref(),
+            HookPoint::PreRepack => self.pre_repack.as_ref(),
+            HookPoint::PostGrade => self.post_grade.as_ref(),
+            HookPoint::PrePush => self.pre_push.as_ref(),
+        }
+    }
+
+    /// Runs the hook for `point` (if one is configured)
+    pub fn run(&self, point: HookPoint, env: &HookEnv) -> Result<HookOutcome> {
+        let Some(cmd) = self.command(point) else {
+            return Ok(HookOutcome::Continue);
+        };
+
+        info!("running {} hook for {}", point, env.team_name);
+
+        let mut command = shell(cmd);
+        command
+            .env("KASM_HOOK", point.to_string())
+            .env("KASM_SHEET_ID", env.sheet_id)
+            .env("KASM_TEAM_NAME", env.team_name)
+            .env("KASM_GRADE", env.grade.unwrap_or(""))
+            .env("KASM_INTERNAL_ID", env.internal_id.unwrap_or(""));
+
+        if let Some(dir) = env.team_dir {
+            let dir = std::fs::canonicalize(dir)?;
+            command.env("KASM_TEAM_DIR", &dir).current_dir(&dir);
+        }
+
+        let status = command.status()?;
+        if status.success() {
+            return Ok(HookOutcome::Continue);
+        }
+
+        match self.on_failure {
+            HookFailure::Abort => Err(KasmError::Hook(format!(
+                "{} hook failed for {} ({})",
+                point, env.team_name, status
+            ))),
+            HookFailure::Skip => {
+                // After the fact, there's nothing left to skip
+                let consequence = match point {
+                    HookPoint::PreRepack | HookPoint::PrePush => "skipping",
+                    HookPoint::PostUnpack | HookPoint::PostGrade => "carrying on",
+                };
+                warn!(
+                    "{} hook failed for {} ({}), {}",
+                    point, env.team_name, status, consequence
+                );
+                Ok(HookOutcome::Skip)
+            }
+        }
+    }
+
+    /// Runs the post-unpack hook for every team in `grades`
+    pub fn run_post_unpack(&self, unpack_path: &Path, grades: &Grades) -> Result<()> {
+        if self.post_unpack.is_none() {
+            return Ok(());
+        }
+
+        for gd in &grades.map {
+            let team_dir = unpack_path.join(&gd.target);
+            let grade = gd.grade.map(|g| g.to_csv());
+            self.run(
+                HookPoint::PostUnpack,
+                &HookEnv {
+                    sheet_id: &grades.sheet_id,
+                    team_name: &gd.target,
+                    team_dir: team_dir.is_dir().then_some(team_dir.as_path()),
+                    grade: grade.as_deref(),
+                    internal_id: gd.internal_id.as_deref(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+}
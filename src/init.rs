@@ -1,13 +1,23 @@
 use crate::config::*;
-use std::{error::Error, fs::*, path::PathBuf};
+use crate::error::{KasmError, Result};
+use std::{fs::*, path::PathBuf};
 
-pub fn init_master(cfg: &MasterCfg) -> Result<(), Box<dyn Error>> {
+pub fn init_master(cfg: &MasterCfg) -> Result<()> {
     let cfg_path: PathBuf = MASTER_CFG_FILENAME.into();
     if cfg_path.is_file() {
-        return Err(format!("{} already exists!", MASTER_CFG_FILENAME).into());
+        return Err(KasmError::Validation(format!(
+            "{} already exists!",
+            MASTER_CFG_FILENAME
+        )));
     }
 
-    write(cfg_path, toml::to_string_pretty(cfg)?)?;
+    cfg.templates.validate()?;
+
+    let mut cfg = cfg.clone();
+    if let Some(ref url) = cfg.moodle_url {
+        cfg.moodle_url = Some(crate::moodle::MoodleUrls::new(url)?.base().to_string());
+    }
+    write(cfg_path, toml::to_string_pretty(&cfg)?)?;
 
     Ok(())
 }
@@ -0,0 +1,452 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::KasmError;
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+
+/// A point in time (seconds). Written as "YYYY-MM-DD HH:MM".
+///
+/// kasm doesn't know about time zones: fetched sheets use UTC (like
+/// Moodle's API), unpacked sheets the local time of the grading worksheet.
+/// Due dates and submissions of a sheet always use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Moment(i64);
+
+/// A duration (seconds). Written like "1d12h", "90m" or "0".
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Span(i64);
+
+/// How late submissions get graded
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LatePolicy {
+    /// Submissions late by at most this much count as on time
+    #[serde(default)]
+    pub grace: Span,
+
+    /// Percent of the grade taken off per started day
+    #[serde(default)]
+    pub percent_per_day: u32,
+
+    /// Submissions later than this get no points at all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cutoff: Option<Span>,
+}
+
+/// What a late submission costs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Penalty {
+    /// Started days late
+    pub days: i64,
+    /// Percent of the grade taken off
+    pub percent: u32,
+}
+
+impl Moment {
+    pub fn from_unix(secs: i64) -> Moment {
+        Moment(secs)
+    }
+
+    /// Parses the dates of Moodle's grading worksheet, e.g.
+    /// "Donnerstag, 11. Mai 2023, 18:41" or "Thursday, 11 May 2023, 6:41 PM".
+    /// "-" (not submitted) and anything unknown yield None.
+    pub fn parse_moodle(s: &str) -> Option<Moment> {
+        let parts: Vec<&str> = s.split(',').map(|p| p.trim()).collect();
+        let [_weekday, date, time] = parts.as_slice() else {
+            return s.parse().ok();
+        };
+
+        let mut date = date.split_whitespace();
+        let day: i64 = date.next()?.trim_end_matches('.').parse().ok()?;
+        let month = month_number(date.next()?)?;
+        let year: i64 = date.next()?.parse().ok()?;
+
+        let (time, meridiem) = match time.split_once(' ') {
+            Some((time, meridiem)) => (time, Some(meridiem.to_ascii_uppercase())),
+            None => (*time, None),
+        };
+        let (hour, minute) = time.split_once(':')?;
+        let mut hour: i64 = hour.parse().ok()?;
+        let minute: i64 = minute.parse().ok()?;
+        match meridiem.as_deref() {
+            Some("PM") if hour < 12 => hour += 12,
+            Some("AM") if hour == 12 => hour = 0,
+            _ => {}
+        }
+
+        Moment::from_parts(year, month, day, hour, minute, 0)
+    }
+
+    fn from_parts(y: i64, m: i64, d: i64, h: i64, min: i64, s: i64) -> Option<Moment> {
+        // Keeps the arithmetic below (and with Spans) far from overflowing
+        if !(1..=9999).contains(&y)
+            || !(1..=12).contains(&m)
+            || !(1..=31).contains(&d)
+            || !(0..24).contains(&h)
+            || !(0..60).contains(&min)
+            || !(0..60).contains(&s)
+        {
+            return None;
+        }
+        Some(Moment(
+            days_from_civil(y, m, d) * DAY + h * HOUR + min * MINUTE + s,
+        ))
+    }
+
+    /// How much later than `earlier` this is (None if it isn't)
+    pub fn after(&self, earlier: Moment) -> Option<Span> {
+        (self.0 > earlier.0).then_some(Span(self.0 - earlier.0))
+    }
+
+    /// `span` later than this (None if that's beyond any date we can represent)
+    pub fn checked_add(&self, span: Span) -> Option<Moment> {
+        self.0.checked_add(span.0).map(Moment)
+    }
+}
+
+fn month_number(name: &str) -> Option<i64> {
+    let prefix: String = name.to_lowercase().chars().take(3).collect();
+    let month = match prefix.as_str() {
+        "jan" => 1,
+        "feb" => 2,
+        "mär" | "mar" => 3,
+        "apr" => 4,
+        "mai" | "may" => 5,
+        "jun" => 6,
+        "jul" => 7,
+        "aug" => 8,
+        "sep" => 9,
+        "okt" | "oct" => 10,
+        "nov" => 11,
+        "dez" | "dec" => 12,
+        _ => return None,
+    };
+    Some(month)
+}
+
+// Howard Hinnant's days_from_civil/civil_from_days
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (m <= 2) as i64, m, d)
+}
+
+impl FromStr for Moment {
+    type Err = KasmError;
+
+    /// "YYYY-MM-DD HH:MM[:SS]" (or with a T instead of the space)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || KasmError::Validation(format!("{:?} is not a valid date (YYYY-MM-DD HH:MM)", s));
+
+        let (date, time) = s.trim().split_once([' ', 'T']).ok_or_else(invalid)?;
+        let num = |n: &str| n.parse::<i64>().map_err(|_| invalid());
+
+        let date: Vec<&str> = date.split('-').collect();
+        let time: Vec<&str> = time.split(':').collect();
+        let (y, m, d) = match date.as_slice() {
+            [y, m, d] => (num(y)?, num(m)?, num(d)?),
+            _ => return Err(invalid()),
+        };
+        let (h, min, sec) = match time.as_slice() {
+            [h, min] => (num(h)?, num(min)?, 0),
+            [h, min, sec] => (num(h)?, num(min)?, num(sec)?),
+            _ => return Err(invalid()),
+        };
+
+        Moment::from_parts(y, m, d, h, min, sec).ok_or_else(invalid)
+    }
+}
+
+impl Display for Moment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (y, m, d) = civil_from_days(self.0.div_euclid(DAY));
+        let secs = self.0.rem_euclid(DAY);
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            y,
+            m,
+            d,
+            secs / HOUR,
+            secs % HOUR / MINUTE
+        )?;
+        if secs % MINUTE != 0 {
+            write!(f, ":{:02}", secs % MINUTE)?;
+        }
+        Ok(())
+    }
+}
+
+impl Span {
+    /// Started days
+    pub fn days(&self) -> i64 {
+        self.0 / DAY + i64::from(self.0 % DAY != 0)
+    }
+}
+
+impl FromStr for Span {
+    type Err = KasmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            KasmError::Validation(format!(
+                "{:?} is not a valid duration (e.g. 2d, 36h or 1d12h)",
+                s
+            ))
+        };
+
+        let s = s.trim();
+        if s == "0" {
+            return Ok(Span(0));
+        }
+
+        let (mut total, mut number) = (0i64, String::new());
+        for c in s.chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let unit = match c {
+                'd' => DAY,
+                'h' => HOUR,
+                'm' => MINUTE,
+                's' => 1,
+                _ => return Err(invalid()),
+            };
+            let n: i64 = number.parse().map_err(|_| invalid())?;
+            total = n
+                .checked_mul(unit)
+                .and_then(|secs| total.checked_add(secs))
+                .ok_or_else(invalid)?;
+            number.clear();
+        }
+
+        if !number.is_empty() || s.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Span(total))
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "0");
+        }
+
+        let mut rest = self.0;
+        for (unit, suffix) in [(DAY, 'd'), (HOUR, 'h'), (MINUTE, 'm'), (1, 's')] {
+            if rest >= unit {
+                write!(f, "{}{}", rest / unit, suffix)?;
+                rest %= unit;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LatePolicy {
+    /// The penalty for being `late_by` late (None if there is none)
+    pub fn penalty(&self, late_by: Span) -> Option<Penalty> {
+        if late_by <= self.grace {
+            return None;
+        }
+
+        let days = late_by.days();
+        let percent = if self.cutoff.is_some_and(|cutoff| late_by > cutoff) {
+            100
+        } else {
+            u32::try_from(days)
+                .unwrap_or(u32::MAX)
+                .saturating_mul(self.percent_per_day)
+                .min(100)
+        };
+        Some(Penalty { days, percent })
+    }
+}
+
+impl Serialize for Moment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Moment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Span {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Span {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moment(s: &str) -> Moment {
+        s.parse().unwrap()
+    }
+
+    fn span(s: &str) -> Span {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_worksheet_dates() {
+        assert_eq!(
+            Moment::parse_moodle("Donnerstag, 11. Mai 2023, 18:41"),
+            Some(moment("2023-05-11 18:41"))
+        );
+        assert_eq!(
+            Moment::parse_moodle("Thursday, 11 May 2023, 6:41 PM"),
+            Some(moment("2023-05-11 18:41"))
+        );
+        assert_eq!(
+            Moment::parse_moodle("Mittwoch, 1. März 2023, 09:05"),
+            Some(moment("2023-03-01 09:05"))
+        );
+        assert_eq!(Moment::parse_moodle("-"), None);
+        assert_eq!(Moment::parse_moodle("Thursday, 11 Foo 2023, 6:41 PM"), None);
+    }
+
+    #[test]
+    fn parses_midnight_and_noon() {
+        assert_eq!(
+            Moment::parse_moodle("Thursday, 11 May 2023, 12:00 AM"),
+            Some(moment("2023-05-11 00:00"))
+        );
+        assert_eq!(
+            Moment::parse_moodle("Thursday, 11 May 2023, 12:30 PM"),
+            Some(moment("2023-05-11 12:30"))
+        );
+        assert_eq!(
+            Moment::parse_moodle("Thursday, 11 May 2023, 1:00 AM"),
+            Some(moment("2023-05-11 01:00"))
+        );
+    }
+
+    #[test]
+    fn moment_round_trips() {
+        for s in [
+            "2023-05-11 18:41",
+            "2024-02-29 00:00",
+            "1999-12-31 23:59:59",
+        ] {
+            assert_eq!(moment(s).to_string(), s);
+        }
+        assert_eq!(moment("2023-05-11T18:41").to_string(), "2023-05-11 18:41");
+        assert!("2023-13-01 00:00".parse::<Moment>().is_err());
+        assert!("2023-05-11".parse::<Moment>().is_err());
+        assert!("999999999999999-01-01 00:00".parse::<Moment>().is_err());
+        assert_eq!(
+            moment("2023-05-11 18:41").checked_add(span("1d")),
+            Some(moment("2023-05-12 18:41"))
+        );
+        assert_eq!(moment("2023-05-11 18:41").checked_add(Span(i64::MAX)), None);
+    }
+
+    #[test]
+    fn span_round_trips() {
+        for s in ["1d12h", "0", "45s", "2d30m", "1h1m1s"] {
+            assert_eq!(span(s).to_string(), s);
+        }
+        assert_eq!(span("36h").to_string(), "1d12h");
+        assert_eq!(span("90m").to_string(), "1h30m");
+        assert_eq!(span("90s").to_string(), "1m30s");
+        for s in [
+            "5",
+            "d",
+            "",
+            "1x",
+            "1d5",
+            "-1d",
+            "999999999999999d",
+            "106751991167300d1d",
+        ] {
+            assert!(s.parse::<Span>().is_err(), "{:?} should be invalid", s);
+        }
+    }
+
+    #[test]
+    fn penalty_starts_after_the_grace_period() {
+        let policy = LatePolicy {
+            grace: span("1h"),
+            percent_per_day: 10,
+            cutoff: None,
+        };
+        assert_eq!(policy.penalty(Span(0)), None);
+        assert_eq!(policy.penalty(span("1h")), None);
+        assert_eq!(
+            policy.penalty(span("1h1s")),
+            Some(Penalty {
+                days: 1,
+                percent: 10
+            })
+        );
+        assert_eq!(
+            policy.penalty(span("1d1s")),
+            Some(Penalty {
+                days: 2,
+                percent: 20
+            })
+        );
+    }
+
+    #[test]
+    fn penalty_cutoff_and_cap() {
+        let policy = LatePolicy {
+            grace: Span(0),
+            percent_per_day: 40,
+            cutoff: Some(span("5d")),
+        };
+        assert_eq!(policy.penalty(span("2d")).unwrap().percent, 80);
+        // Capped, even before the cutoff
+        assert_eq!(policy.penalty(span("3d")).unwrap().percent, 100);
+
+        let policy = LatePolicy {
+            percent_per_day: 10,
+            ..policy
+        };
+        assert_eq!(policy.penalty(span("5d")).unwrap().percent, 50);
+        assert_eq!(
+            policy.penalty(span("5d1s")),
+            Some(Penalty {
+                days: 6,
+                percent: 100
+            })
+        );
+    }
+}
@@ -1,8 +1,17 @@
 pub mod args;
 pub mod config;
+pub mod error;
 pub mod fetch;
 pub mod grade;
+pub mod gradevalue;
 pub mod gradingtable;
+pub mod hooks;
 pub mod init;
+pub mod late;
+pub mod moodle;
 pub mod repack;
+pub mod similarity;
+pub mod status;
+pub mod template;
+pub mod token;
 pub mod unpack;
@@ -1,6 +1,6 @@
+use std::process::ExitCode;
+
 use clap::Parser;
-use kasm::config::Structure;
-use kasm::config::UNPACK_PATH_FILENAME_BASE;
 use kasm::grade::grade;
 use kasm::repack::repack;
 use kasm::unpack::unpack;
@@ -8,78 +8,78 @@ use kasm::unpack::unpack;
 use kasm::args::Verb;
 use kasm::config::Grades;
 use kasm::config::MasterCfg;
-use kasm::config::UNPACK_GRADES_FILENAME;
+use kasm::error::Result;
 use log::{error, info};
 
 const DEF_LOG_LEVEL: &str = "info";
 const ENV_LOG_LEVEL: &str = "RUST_LOG";
 
-fn main() {
+fn main() -> ExitCode {
     if std::env::var(ENV_LOG_LEVEL).is_err() {
         std::env::set_var(ENV_LOG_LEVEL, DEF_LOG_LEVEL);
     }
     pretty_env_logger::init();
     let command = kasm::args::Cli::parse();
 
-    if let Verb::Init(ref cfg) = command.verb {
-        kasm::init::init_master(cfg).unwrap();
-        return;
-    }
-
-    let master = match MasterCfg::resolve() {
-        Ok(cfg) => cfg,
+    match run(command.verb) {
+        Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
-            error!("MasterCfg::resolve() returned error {:?}", e);
-            error!("could not read config. run \"kasm init\" first!");
-            panic!()
+            error!("{}", e);
+            ExitCode::from(e.exit_code())
         }
-    };
-
-    if master.unpack_structure != Structure::Groups {
-        panic!("Only unpack_structure = \"Groups\" is currently supported.");
     }
+}
 
-    if master.recursive_unzip {
-        error!("recursive unzip is not implemented yet, continuing");
+fn run(verb: Verb) -> Result<()> {
+    if let Verb::Init(ref cfg) = verb {
+        return kasm::init::init_master(cfg);
     }
 
-    let grades = Grades::resolve();
+    let master = MasterCfg::resolve().inspect_err(|_| {
+        info!("could not read config. run \"kasm init\" first!");
+    })?;
 
-    match command.verb {
-        Verb::Unpack(cfg) => {
-            unpack(&master, &cfg).unwrap();
-        }
-        Verb::Grade(cfg) => {
-            if let Ok(grades) = grades {
-                grade(&master, &cfg, &grades).unwrap()
-            } else {
-                error!("{} could not be found!", UNPACK_GRADES_FILENAME);
+    // Only some verbs need (and look for) a grades.toml
+    let grades = || {
+        Grades::resolve().inspect_err(|_| {
+            info!(
+                "run this command from inside an {:?} directory",
+                master.templates.unpack_dir("xx").unwrap_or_default()
+            );
+        })
+    };
+
+    match verb {
+        Verb::Init(_) => Ok(()),
+        Verb::Unpack(cfg) => unpack(&master, &cfg),
+        Verb::Grade(cfg) => grade(&master, &cfg, &grades()?),
+        Verb::Repack(cfg) => repack(&master, &cfg),
+        Verb::SetupFetch => kasm::fetch::setup(&master),
+        Verb::Fetch(cfg) => kasm::fetch::MoodleFetcher::new(&master)?.fetch(&cfg),
+        Verb::Status(cfg) => match cfg.sheet_id {
+            Some(sheet_id) => {
+                kasm::status::status(&master, &Grades::for_sheet(&master, &sheet_id)?)
             }
+            None => kasm::status::status(&master, &grades()?),
+        },
+        Verb::Push(cfg) => {
+            let grades = grades()?;
+            kasm::fetch::MoodleFetcher::new(&master)?.push_grades(&grades, cfg.dry_run, cfg.files)
         }
-        Verb::Repack(cfg) => {
-            repack(&master, &cfg).unwrap();
-        }
-        Verb::SetupFetch => {
-            kasm::fetch::setup(&master).unwrap();
-        }
-        Verb::Fetch(_cfg) => {
-            kasm::fetch::MoodleFetcher::new(&master)
-                .interactive_dl()
-                .unwrap();
+        Verb::Diff => {
+            let grades = grades()?;
+            kasm::fetch::MoodleFetcher::new(&master)?.diff(&grades)
         }
-        Verb::Push(cfg) => {
-            if let Ok(ref grades) = grades {
-                kasm::fetch::MoodleFetcher::new(&master)
-                    .push_grades(grades, cfg.dry_run)
-                    .unwrap();
+        Verb::Similarity(cfg) => {
+            let sheets = if cfg.sheet_ids.is_empty() {
+                vec![grades()?]
             } else {
-                error!("{} could not be found!", UNPACK_GRADES_FILENAME);
-                info!(
-                    "run this command from inside an {}xx directory",
-                    UNPACK_PATH_FILENAME_BASE
-                );
-            }
+                cfg.sheet_ids
+                    .iter()
+                    .map(|sheet_id| Grades::for_sheet(&master, sheet_id))
+                    .collect::<Result<Vec<_>>>()?
+            };
+            kasm::similarity::similarity(&master, &cfg, &sheets)
         }
-        _ => panic!("unexpected verb"),
     }
 }
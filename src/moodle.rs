@@ -0,0 +1,409 @@
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::debug;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+
+const MOODLE_REST_PATH: &str = "/webservice/rest/server.php";
+const MOODLE_UPLOAD_PATH: &str = "/webservice/upload.php";
+const MOODLE_PLUGINFILE_PATH: &str = "/webservice/pluginfile.php";
+
+/// 15 Min Timeout because Moodle is _fast_
+const MOODLE_TIMEOUT: Duration = Duration::from_secs(900);
+
+/// Errors talking to Moodle's web services
+#[derive(Debug)]
+pub enum MoodleError {
+    /// The base URL isn't usable
+    InvalidUrl(String),
+    /// The request itself failed (network, TLS, HTTP status)
+    Http(reqwest::Error),
+    /// Moodle answered with an exception (invalid token, missing capability, ...)
+    Exception {
+        wsfunction: String,
+        errorcode: String,
+        message: String,
+    },
+    /// Moodle's answer doesn't look like what we expected
+    Decode {
+        wsfunction: String,
+        source: serde_json::Error,
+    },
+    /// Reading a file we want to upload failed
+    Io(std::io::Error),
+}
+
+impl Display for MoodleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoodleError::InvalidUrl(url) => write!(f, "{} is not a valid http(s) URL", url),
+            MoodleError::Http(e) => write!(f, "request to moodle failed: {}", e),
+            MoodleError::Exception {
+                wsfunction,
+                errorcode,
+                message,
+            } => write!(
+                f,
+                "moodle error [{}] in {}: {}",
+                errorcode, wsfunction, message
+            ),
+            MoodleError::Decode { wsfunction, source } => {
+                write!(f, "unexpected response to {}: {}", wsfunction, source)
+            }
+            MoodleError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for MoodleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MoodleError::Http(e) => Some(e),
+            MoodleError::Decode { source, .. } => Some(source),
+            MoodleError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for MoodleError {
+    fn from(e: reqwest::Error) -> Self {
+        // The URL contains the token
+        MoodleError::Http(e.without_url())
+    }
+}
+
+impl From<std::io::Error> for MoodleError {
+    fn from(e: std::io::Error) -> Self {
+        MoodleError::Io(e)
+    }
+}
+
+/// Endpoints of a Moodle instance, derived from its base URL
+#[derive(Debug, Clone)]
+pub struct MoodleUrls {
+    base: String,
+}
+
+impl MoodleUrls {
+    pub fn new(base: &str) -> Result<MoodleUrls, MoodleError> {
+        let parsed =
+            reqwest::Url::parse(base.trim()).map_err(|_| MoodleError::InvalidUrl(base.into()))?;
+        if !["http", "https"].contains(&parsed.scheme()) {
+            return Err(MoodleError::InvalidUrl(base.into()));
+        }
+
+        Ok(MoodleUrls {
+            base: parsed.as_str().trim_end_matches('/').to_string(),
+        })
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    pub fn rest(&self) -> String {
+        self.base.clone() + MOODLE_REST_PATH
+    }
+
+    pub fn upload(&self) -> String {
+        self.base.clone() + MOODLE_UPLOAD_PATH
+    }
+
+    /// Rebases a file URL returned by Moodle onto our base URL (Moodle builds
+    /// them from its own idea of its address, which might not be reachable)
+    pub fn pluginfile(&self, fileurl: &str) -> String {
+        match fileurl.split_once(MOODLE_PLUGINFILE_PATH) {
+            Some((_, path)) => format!("{}{}{}", self.base, MOODLE_PLUGINFILE_PATH, path),
+            None => fileurl.to_string(),
+        }
+    }
+}
+
+/// `core_webservice_get_site_info`
+#[derive(Debug, Clone, Deserialize)]
+pub struct SiteInfo {
+    pub sitename: String,
+    pub fullname: String,
+}
+
+/// `mod_assign_get_assignments`
+#[derive(Debug, Clone, Deserialize)]
+pub struct AssignmentsResponse {
+    pub courses: Vec<CourseAssignments>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CourseAssignments {
+    #[serde(default)]
+    pub assignments: Vec<Assignment>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Assignment {
+    pub id: u64,
+    pub name: String,
+    /// Maximum grade. Negative values are scale ids.
+    #[serde(default)]
+    pub grade: f64,
+    /// Unix time, 0 if there is none
+    #[serde(default)]
+    pub duedate: i64,
+}
+
+/// `mod_assign_get_submissions`
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubmissionsResponse {
+    pub assignments: Vec<AssignmentSubmissions>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AssignmentSubmissions {
+    #[serde(default)]
+    pub submissions: Vec<Submission>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Submission {
+    pub groupid: u64,
+    pub status: String,
+    /// Unix time of the last change
+    #[serde(default)]
+    pub timemodified: i64,
+    #[serde(default)]
+    pub plugins: Vec<SubmissionPlugin>,
+}
+
+impl Submission {
+    /// All files the students submitted
+    pub fn files(&self) -> impl Iterator<Item = &SubmissionFile> {
+        self.plugins
+            .iter()
+            .flat_map(|plug| &plug.fileareas)
+            .filter(|fa| fa.area == "submission_files")
+            .flat_map(|fa| &fa.files)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubmissionPlugin {
+    #[serde(default)]
+    pub fileareas: Vec<FileArea>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileArea {
+    pub area: String,
+    #[serde(default)]
+    pub files: Vec<SubmissionFile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubmissionFile {
+    pub filename: String,
+    pub fileurl: String,
+    #[serde(default)]
+    pub filesize: u64,
+    #[serde(default)]
+    pub timemodified: i64,
+}
+
+/// An entry of `mod_assign_list_participants`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Participant {
+    pub id: u64,
+    #[serde(default)]
+    pub groupid: Option<u64>,
+    #[serde(default)]
+    pub groupname: Option<String>,
+    #[serde(default)]
+    pub submissionstatus: String,
+    /// "Participant N" if the assignment uses blind marking
+    #[serde(default)]
+    pub fullname: String,
+    /// The participant id of feedback zips (-1 if Moodle hasn't assigned one yet)
+    #[serde(default = "no_recordid")]
+    pub recordid: i64,
+}
+
+fn no_recordid() -> i64 {
+    -1
+}
+
+/// `mod_assign_get_participant`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ParticipantDetails {
+    pub fullname: String,
+    #[serde(default)]
+    pub groupname: Option<String>,
+}
+
+/// `mod_assign_get_grades`
+#[derive(Debug, Clone, Deserialize)]
+pub struct GradesResponse {
+    #[serde(default)]
+    pub assignments: Vec<AssignmentGrades>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AssignmentGrades {
+    #[serde(default)]
+    pub grades: Vec<UserGrade>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserGrade {
+    pub userid: u64,
+    #[serde(default)]
+    pub attemptnumber: i64,
+    /// e.g. "10.50000". Negative if the user has no grade (yet).
+    pub grade: String,
+}
+
+/// An entry of upload.php's answer
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadedFile {
+    pub itemid: u64,
+}
+
+/// Thin typed wrapper around Moodle's REST web service
+#[derive(Debug, Clone)]
+pub struct MoodleClient {
+    pub urls: MoodleUrls,
+    token: String,
+    http: reqwest::blocking::Client,
+}
+
+impl MoodleClient {
+    pub fn new(urls: MoodleUrls, token: String) -> Result<MoodleClient, MoodleError> {
+        Ok(MoodleClient {
+            urls,
+            token,
+            http: reqwest::blocking::Client::builder()
+                .timeout(MOODLE_TIMEOUT)
+                .build()?,
+        })
+    }
+
+    /// Calls `wsfunction` with `params` and decodes the answer as `T`.
+    /// Moodle's exception envelope is turned into [`MoodleError::Exception`].
+    pub fn call<T: DeserializeOwned>(
+        &self,
+        wsfunction: &str,
+        params: &[(&str, &str)],
+    ) -> Result<T, MoodleError> {
+        let resp = self
+            .http
+            .get(self.urls.rest())
+            .query(&[
+                ("moodlewsrestformat", "json"),
+                ("wsfunction", wsfunction),
+                ("wstoken", self.token.as_str()),
+            ])
+            .query(params)
+            .send()?
+            .error_for_status()?;
+
+        let text = resp.text()?;
+        // Not the body itself, it's full of personal data
+        debug!("{} -> {} bytes", wsfunction, text.len());
+        Self::decode(wsfunction, &text)
+    }
+
+    fn decode<T: DeserializeOwned>(wsfunction: &str, text: &str) -> Result<T, MoodleError> {
+        let decode_err = |source| MoodleError::Decode {
+            wsfunction: wsfunction.to_string(),
+            source,
+        };
+
+        let value: Value = serde_json::from_str(text).map_err(decode_err)?;
+        if let Some(exception) = value.get("exception").or_else(|| value.get("error")) {
+            let field = |name: &str| {
+                value
+                    .get(name)
+                    .and_then(|v| v.as_str())
+                    .map(|s| s.to_string())
+            };
+            return Err(MoodleError::Exception {
+                wsfunction: wsfunction.to_string(),
+                errorcode: field("errorcode").unwrap_or_else(|| "unknown".into()),
+                message: field("message")
+                    .or_else(|| exception.as_str().map(|s| s.to_string()))
+                    .unwrap_or_default(),
+            });
+        }
+
+        serde_json::from_value(value).map_err(decode_err)
+    }
+
+    pub fn site_info(&self) -> Result<SiteInfo, MoodleError> {
+        self.call("core_webservice_get_site_info", &[])
+    }
+
+    /// Uploads `files` into a new draft area. All returned entries
+    /// share the same item id.
+    pub fn upload_draft_files(&self, files: &[PathBuf]) -> Result<Vec<UploadedFile>, MoodleError> {
+        let mut form = reqwest::blocking::multipart::Form::new()
+            .text("token", self.token.clone())
+            .text("filearea", "draft")
+            // 0 creates a new draft area
+            .text("itemid", "0");
+        for (i, file) in files.iter().enumerate() {
+            form = form.file(format!("file_{}", i + 1), file)?;
+        }
+
+        let text = self
+            .http
+            .post(self.urls.upload())
+            .multipart(form)
+            .send()?
+            .error_for_status()?
+            .text()?;
+
+        Self::decode("upload.php", &text)
+    }
+
+    /// Streams a file into `path`, calling `progress` with the size of every
+    /// chunk written. The file only shows up once it's complete.
+    pub fn download_to(
+        &self,
+        fileurl: &str,
+        path: &Path,
+        mut progress: impl FnMut(u64),
+    ) -> Result<(), MoodleError> {
+        let mut resp = self
+            .http
+            .get(self.urls.pluginfile(fileurl))
+            .query(&[("token", self.token.as_str())])
+            .send()?
+            .error_for_status()?;
+
+        let mut part_name = path.file_name().unwrap_or_default().to_os_string();
+        part_name.push(".part");
+        let part = path.with_file_name(part_name);
+
+        let result = (|| {
+            let mut out = std::fs::File::create(&part)?;
+            let mut buf = [0u8; 64 * 1024];
+            loop {
+                let n = resp.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                out.write_all(&buf[..n])?;
+                progress(n as u64);
+            }
+            std::fs::rename(&part, path)
+        })();
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&part);
+        }
+        Ok(result?)
+    }
+}
@@ -1,34 +1,132 @@
 use log::{error, info, warn};
 use std::io::Write;
 use std::{
-    error::Error,
     fs::File,
-    path::PathBuf,
+    path::{Path, PathBuf},
     time::{self, UNIX_EPOCH},
 };
 
 use crate::config::{Source, Structure};
+use crate::error::{KasmError, Result};
 use crate::{
     args::RepackDir,
     config::{
-        Grades, MasterCfg, UNPACK_CSV_FILENAME, UNPACK_GRADES_FILENAME, UNPACK_PATH_FILENAME_BASE,
+        Grades, MasterCfg, FEEDBACK_FILENAME, POINTS_FILENAME, UNPACK_CSV_FILENAME,
+        UNPACK_GRADES_FILENAME,
     },
-    gradingtable::GradingRecord,
+    gradingtable::{GradingRecord, GradingTable, GradingTableWriter},
+    hooks::{HookEnv, HookOutcome, HookPoint, Hooks},
+    template::Templates,
 };
 
-pub fn repack(master: &MasterCfg, cfg: &RepackDir) -> Result<(), Box<dyn Error>> {
-    let unpacked_path: PathBuf = (UNPACK_PATH_FILENAME_BASE.to_string() + &cfg.sheet_id).into();
+/// Everything the repacking strategies need to know about the
+/// unpacked directory.
+pub struct RepackCtx<'a> {
+    /// The unpacked sheet directory
+    pub unpacked_path: &'a Path,
+    /// Filtered grading worksheet (empty for autofetch)
+    pub grading_table: &'a [GradingRecord],
+    /// Parsed grades.toml
+    pub grades: &'a Grades,
+    /// Master regex (group, team)
+    pub reg: &'a regex::Regex,
+    /// Decides which files get repacked
+    pub filter: &'a RepackFilter,
+    /// Script hooks
+    pub hooks: &'a Hooks,
+    /// Naming templates
+    pub templates: &'a Templates,
+}
+
+impl RepackCtx<'_> {
+    /// Runs the pre-repack hook for the team/student in `dir`
+    fn pre_repack(&self, dir: &Path, target: &str) -> Result<HookOutcome> {
+        let gd = self.grades.map.iter().find(|gd| gd.target == target);
+        let grade = gd
+            .and_then(|gd| self.grades.final_grade(gd))
+            .map(|g| g.to_csv());
+        self.hooks.run(
+            HookPoint::PreRepack,
+            &HookEnv {
+                sheet_id: &self.grades.sheet_id,
+                team_name: target,
+                team_dir: Some(dir),
+                grade: grade.as_deref(),
+                internal_id: gd.and_then(|gd| gd.internal_id.as_deref()),
+            },
+        )
+    }
+}
+
+/// Decides which files of a team's directory get shipped as feedback
+///
+/// Only files directly inside the directory count. Subdirectories (like the
+/// ones nested zips get extracted into) are never shipped.
+pub struct RepackFilter {
+    /// Individual files get filtered against this
+    internal_reg: regex::Regex,
+}
+
+impl RepackFilter {
+    pub fn new(master: &MasterCfg) -> Result<RepackFilter> {
+        Ok(RepackFilter {
+            internal_reg: regex::Regex::new(match master.repack_filter {
+                None => "",
+                Some(ref filter) => filter,
+            })?,
+        })
+    }
+
+    /// Lists the files in `dir` that should be shipped as feedback
+    pub fn files(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+
+        for f in dir.read_dir()?.filter_map(|f| f.ok()) {
+            let file_name = f.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                warn!("skipping non-UTF-8 file name {:?}", f.file_name());
+                continue;
+            };
+
+            // Match files against the second regex. Textual feedback
+            // goes into the csv/Moodle instead.
+            if !f.path().is_file()
+                || !self.internal_reg.is_match(file_name)
+                || file_name == FEEDBACK_FILENAME
+            {
+                continue;
+            }
+
+            files.push(f.path());
+        }
+
+        Ok(files)
+    }
+}
+
+type RepackFn = fn(
+    &RepackCtx,
+    &mut zip::ZipWriter<File>,
+    &zip::write::FileOptions,
+    Option<GradingTableWriter>,
+) -> Result<()>;
+
+pub fn repack(master: &MasterCfg, cfg: &RepackDir) -> Result<()> {
+    let unpacked_path = master.templates.unpack_dir(&cfg.sheet_id)?;
 
     let packing_time = time::SystemTime::now()
-        .duration_since(UNIX_EPOCH)?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
         .as_millis()
         .to_string();
 
     // Build the repacked zip name
-    let zip_name: PathBuf = format!("feedback_{}_{}.zip", cfg.sheet_id, &packing_time).into();
+    let zip_name = master
+        .templates
+        .feedback_zip(&cfg.sheet_id, &packing_time)?;
 
     // Build the new csv name
-    let grading_csv_name: PathBuf = format!("grades_{}_{}.csv", cfg.sheet_id, &packing_time).into();
+    let grading_csv_name = master.templates.grades_csv(&cfg.sheet_id, &packing_time)?;
 
     // Check that we have all that we need
     // - Unpacked dir
@@ -36,45 +134,47 @@ pub fn repack(master: &MasterCfg, cfg: &RepackDir) -> Result<(), Box<dyn Error>>
     // - .filtered.csv
 
     if !unpacked_path.is_dir() {
-        error!("the {:?} is not a directory", unpacked_path);
-        return Err("".into());
+        return Err(KasmError::Validation(format!(
+            "the {:?} is not a directory",
+            unpacked_path
+        )));
     }
 
     if !unpacked_path.join(UNPACK_GRADES_FILENAME).is_file() {
-        error!("grades file could not be found in {:?}", unpacked_path);
-        return Err("".into());
+        return Err(KasmError::Config(format!(
+            "grades file could not be found in {:?}",
+            unpacked_path
+        )));
     }
 
     // Parse stuff
-    let grades: Grades = toml::from_str(&std::fs::read_to_string(
+    let mut grades: Grades = toml::from_str(&std::fs::read_to_string(
         unpacked_path.join(UNPACK_GRADES_FILENAME),
     )?)?;
+    grades.location = unpacked_path.join(UNPACK_GRADES_FILENAME);
+    grades.validate(master)?;
     let reg = regex::Regex::new(&master.groups_regex)?;
-
-    // Individual files get filtered against this
-    let internal_reg = regex::Regex::new(match master.repack_filter {
-        None => "",
-        Some(ref filter) => filter,
-    })?;
+    let filter = RepackFilter::new(master)?;
 
     if !unpacked_path.join(UNPACK_CSV_FILENAME).is_file() && grades.source == Source::CsvAndZip {
-        error!(
+        return Err(KasmError::Config(format!(
             "filtered csv file could not be found in {:?}",
             unpacked_path
-        );
-        return Err("".into());
+        )));
     }
 
-    let grading_table =
-        GradingRecord::from_csv(&unpacked_path.join(UNPACK_CSV_FILENAME)).unwrap_or(Vec::new());
+    let grading_table = if grades.source == Source::CsvAndZip {
+        GradingTable::from_csv(
+            &unpacked_path.join(UNPACK_CSV_FILENAME),
+            master.csv_headers.as_ref(),
+        )?
+    } else {
+        GradingTable::default()
+    };
 
+    // The new csv gets the same headers as the one we unpacked
     let csv_writer = if grades.source == Source::CsvAndZip {
-        Some(
-            csv::WriterBuilder::new()
-                .delimiter(b',')
-                .quote_style(csv::QuoteStyle::Always)
-                .from_path(grading_csv_name)?,
-        )
+        Some(grading_table.writer(&grading_csv_name)?)
     } else {
         None
     };
@@ -86,22 +186,24 @@ pub fn repack(master: &MasterCfg, cfg: &RepackDir) -> Result<(), Box<dyn Error>>
         .compression_method(zip::CompressionMethod::Deflated)
         .compression_level(Some(9));
 
-    let repack_fn = match (&master.unpack_structure, &master.repack_structure) {
+    let repack_fn: RepackFn = match (&master.unpack_structure, &master.repack_structure) {
         (Structure::Groups, Structure::Groups) => repack_g2g,
         (Structure::Groups, Structure::Individuals) => repack_g2i,
-        _ => todo!(),
+        (Structure::Individuals, Structure::Individuals) => repack_i2i,
+        (Structure::Individuals, Structure::Groups) => repack_i2g,
     };
 
-    repack_fn(
-        &unpacked_path,
-        &grading_table,
-        &grades,
-        &reg,
-        &internal_reg,
-        &mut zip_writer,
-        &zip_options,
-        csv_writer,
-    )?;
+    let ctx = RepackCtx {
+        unpacked_path: &unpacked_path,
+        grading_table: &grading_table.records,
+        grades: &grades,
+        reg: &reg,
+        filter: &filter,
+        hooks: &master.hooks,
+        templates: &master.templates,
+    };
+
+    repack_fn(&ctx, &mut zip_writer, &zip_options, csv_writer)?;
 
     if grades.source == Source::Autofetch {
         warn!("source is autofetch: no .csv was generated!");
@@ -111,70 +213,121 @@ pub fn repack(master: &MasterCfg, cfg: &RepackDir) -> Result<(), Box<dyn Error>>
     Ok(())
 }
 
-pub fn repack_g2i(
-    unpacked_path: &PathBuf,
-    grading_table: &[GradingRecord],
-    grades: &Grades,
-    reg: &regex::Regex,
-    internal_reg: &regex::Regex,
+/// Packs all files in `dir` that pass the repack filter into the
+/// directory `dir_new_name` inside the zip, along with the points
+/// per task of `target` (if graded task by task)
+fn pack_dir(
+    ctx: &RepackCtx,
+    dir: &Path,
+    target: &str,
+    dir_new_name: &str,
     zip_writer: &mut zip::ZipWriter<File>,
     zip_options: &zip::write::FileOptions,
-    mut csv_writer: Option<csv::Writer<File>>,
-) -> Result<(), Box<dyn Error>> {
-    if csv_writer.is_none() || grades.source == Source::Autofetch {
-        error!("Group2Individual Repacking is not supported for Autofetch workflows (yet)");
-        return Err("".into());
+) -> Result<()> {
+    if let Some(breakdown) = ctx.grades.breakdown_for(target) {
+        zip_writer.start_file(
+            format!("{}/{}", dir_new_name, POINTS_FILENAME),
+            *zip_options,
+        )?;
+        zip_writer.write_all(breakdown.as_bytes())?;
     }
 
+    for f in ctx.filter.files(dir)? {
+        info!("packing {:?}", f);
+        // Repack each file
+        let Some(file_name) = f.file_name().map(|name| name.to_string_lossy()) else {
+            continue;
+        };
+        zip_writer.start_file(format!("{}/{}", dir_new_name, file_name), *zip_options)?;
+        zip_writer.write_all(&std::fs::read(&f)?)?;
+    }
+
+    Ok(())
+}
+
+pub fn repack_g2i(
+    ctx: &RepackCtx,
+    zip_writer: &mut zip::ZipWriter<File>,
+    zip_options: &zip::write::FileOptions,
+    mut csv_writer: Option<GradingTableWriter>,
+) -> Result<()> {
     // Start packing stuff
-    std::fs::read_dir(unpacked_path)?
+    for filtered in std::fs::read_dir(ctx.unpacked_path)?
         .filter_map(|entry| entry.ok())
         // Check that whatever we're packing is a _directory_
         // and it matches the master regex
         .filter(|entry| {
-            entry.path().is_dir() && reg.is_match(entry.file_name().to_str().unwrap_or(""))
+            entry.path().is_dir() && ctx.reg.is_match(entry.file_name().to_str().unwrap_or(""))
         })
-        .for_each(|filtered| {
-            info!("filtered: {:?}", filtered.file_name());
-            let dir_name = filtered.file_name();
-            let group_id = dir_name.to_str().unwrap();
-            grades
-                .collect_students_for_group(grading_table, group_id)
-                .iter()
-                .for_each(|studi| {
-                    // Write the student's record to the csv
-                    if let Some(ref mut writer) = csv_writer {
-                        writer.serialize(studi).unwrap();
+    {
+        info!("filtered: {:?}", filtered.file_name());
+        let dir_name = filtered.file_name();
+        let Some(group_id) = dir_name.to_str() else {
+            continue;
+        };
+
+        if ctx.pre_repack(&filtered.path(), group_id)? == HookOutcome::Skip {
+            continue;
+        }
+
+        // (name, participant id) of every member
+        let mut students: Vec<(String, String)> = Vec::new();
+        if ctx.grades.source == Source::Autofetch {
+            // Fetch remembered the members, there is no csv
+            let Some(gd) = ctx.grades.map.iter().find(|gd| gd.target == group_id) else {
+                error!("Group name ({group_id}) not found. Skipping.");
+                continue;
+            };
+            if gd.participants.is_empty() {
+                error!("({group_id}) has no known members. Fetch it again to repack it.");
+            }
+            for (userid, member) in &gd.participants {
+                match member.participant_id {
+                    Some(ref participant_id) => {
+                        students.push((member.name.clone(), participant_id.clone()))
                     }
+                    None => error!(
+                        "{} ({userid}) of {group_id} doesn't have a participant id. Skipping.",
+                        member.name
+                    ),
+                }
+            }
+        } else {
+            let feedback = ctx.grades.feedback_html_for(group_id)?;
+            for mut studi in ctx
+                .grades
+                .collect_students_for_group(ctx.grading_table, group_id)
+            {
+                if let Some(ref feedback) = feedback {
+                    studi.feedback_comment = feedback.clone();
+                }
+
+                // Write the student's record to the csv
+                if let Some(ref mut writer) = csv_writer {
+                    writer.serialize(&studi)?;
+                }
+                students.push((studi.name.clone(), studi.participant_id().to_owned()));
+            }
+        }
+
+        for (name, participant_id) in students {
+            // New directory name. Should be something like
+            // Übungsgruppe AB -- Abgabeteam XY_Name, \
+            // Vorname-12345678_assignsubmission_file_
+            let dir_new_name =
+                ctx.templates
+                    .member_submission_dir(group_id, &name, &participant_id)?;
 
-                    // New directory name. Should be something like
-                    // Übungsgruppe AB -- Abgabeteam XY_Name, \
-                    // Vorname-12345678_assignsubmission_file_
-                    let dir_new_name: String = format!(
-                        "{group_id}_{s_name}_{s_id}_assignsubmission_file_",
-                        group_id = group_id,
-                        s_name = studi.name,
-                        s_id = studi.internal_id.strip_prefix("Teilnehmer/in").unwrap()
-                    );
-
-                    filtered
-                        .path()
-                        .read_dir()
-                        .unwrap()
-                        .filter_map(|f| f.ok())
-                        // Match files against the second regex
-                        .filter(|f| internal_reg.is_match(f.file_name().to_str().unwrap()))
-                        .for_each(|f| {
-                            info!("packing {:?}", f);
-                            // Repack each file
-                            let fname =
-                                format!("{}/{}", dir_new_name, f.file_name().to_str().unwrap());
-                            zip_writer.start_file(fname, *zip_options).unwrap();
-                            let bytes = std::fs::read(f.path()).unwrap();
-                            zip_writer.write_all(&bytes).unwrap();
-                        });
-                });
-        });
+            pack_dir(
+                ctx,
+                &filtered.path(),
+                group_id,
+                &dir_new_name,
+                zip_writer,
+                zip_options,
+            )?;
+        }
+    }
     if let Some(ref mut writer) = csv_writer {
         writer.flush()?;
     }
@@ -184,75 +337,223 @@ pub fn repack_g2i(
 }
 
 pub fn repack_g2g(
-    unpacked_path: &PathBuf,
-    grading_table: &[GradingRecord],
-    grades: &Grades,
-    reg: &regex::Regex,
-    internal_reg: &regex::Regex,
+    ctx: &RepackCtx,
     zip_writer: &mut zip::ZipWriter<File>,
     zip_options: &zip::write::FileOptions,
-    mut csv_writer: Option<csv::Writer<File>>,
-) -> Result<(), Box<dyn Error>> {
+    mut csv_writer: Option<GradingTableWriter>,
+) -> Result<()> {
     // Start packing stuff
-    std::fs::read_dir(unpacked_path)?
+    for filtered in std::fs::read_dir(ctx.unpacked_path)?
         .filter_map(|entry| entry.ok())
         // Check that whatever we're packing is a _directory_
         // and it matches the master regex
         .filter(|entry| {
-            entry.path().is_dir() && reg.is_match(entry.file_name().to_str().unwrap_or(""))
+            entry.path().is_dir() && ctx.reg.is_match(entry.file_name().to_str().unwrap_or(""))
         })
-        .for_each(|filtered| {
-            info!("filtered: {:?}", filtered.file_name());
-            let dir_name = filtered.file_name();
-            let group_name = dir_name.to_str().unwrap();
+    {
+        info!("filtered: {:?}", filtered.file_name());
+        let dir_name = filtered.file_name();
+        let Some(group_name) = dir_name.to_str() else {
+            continue;
+        };
 
-            if let Some(ref mut writer) = csv_writer {
-                grades
-                    .collect_students_for_group(grading_table, group_name)
-                    .iter()
-                    .for_each(|studi| {
-                        // Write the student's record to the csv
-                        writer.serialize(studi).unwrap();
-                    });
-            }
+        if ctx.pre_repack(&filtered.path(), group_name)? == HookOutcome::Skip {
+            continue;
+        }
 
-            let group_id = match grades
-                .map
-                .iter()
-                .find(|m| m.target == group_name)
-                .map(|m| m.internal_id.clone())
+        if let Some(ref mut writer) = csv_writer {
+            let feedback = ctx.grades.feedback_html_for(group_name)?;
+            for mut studi in ctx
+                .grades
+                .collect_students_for_group(ctx.grading_table, group_name)
             {
-                Some(Some(internal_id)) => internal_id,
-                Some(None) => {
-                    error!("Group name ({group_name}) not found. Skipping.");
-                    return;
+                if let Some(ref feedback) = feedback {
+                    studi.feedback_comment = feedback.clone();
                 }
-                None => {
-                    error!("({group_name}) doens't have an internal ID. Can't repack. Skipping.");
-                    return;
+
+                // Write the student's record to the csv
+                writer.serialize(&studi)?;
+            }
+        }
+
+        let Some(gd) = ctx.grades.map.iter().find(|m| m.target == group_name) else {
+            error!("Group name ({group_name}) not found. Skipping.");
+            continue;
+        };
+        // Moodle wants a member's participant id here. Fetched sheets
+        // only know the group id as internal id.
+        let group_id = match ctx.grades.source {
+            Source::CsvAndZip => gd.internal_id.as_deref(),
+            Source::Autofetch => gd.participant_id(),
+        };
+        let Some(group_id) = group_id else {
+            match ctx.grades.source {
+                Source::CsvAndZip => {
+                    error!("({group_name}) doesn't have an internal ID. Can't repack. Skipping.")
                 }
-            };
+                Source::Autofetch => error!(
+                    "({group_name}): no participant id known, fetch the sheet again. Skipping."
+                ),
+            }
+            continue;
+        };
+
+        // New directory name. Should be something like
+        // Übungsgruppe AB -- Abgabeteam XY_12345678_assignsubmission_file
+        let dir_new_name = ctx.templates.group_submission_dir(group_name, group_id)?;
+
+        pack_dir(
+            ctx,
+            &filtered.path(),
+            group_name,
+            &dir_new_name,
+            zip_writer,
+            zip_options,
+        )?;
+    }
+    if let Some(ref mut writer) = csv_writer {
+        writer.flush()?;
+    }
+    zip_writer.flush()?;
+
+    Ok(())
+}
+
+pub fn repack_i2i(
+    ctx: &RepackCtx,
+    zip_writer: &mut zip::ZipWriter<File>,
+    zip_options: &zip::write::FileOptions,
+    mut csv_writer: Option<GradingTableWriter>,
+) -> Result<()> {
+    if csv_writer.is_none() || ctx.grades.source == Source::Autofetch {
+        return Err(KasmError::Validation(
+            "Individual2Individual Repacking is not supported for Autofetch workflows (yet)".into(),
+        ));
+    }
+
+    // Students' directories are named after their grade targets
+    for filtered in std::fs::read_dir(ctx.unpacked_path)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.path().is_dir()
+                && entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|name| ctx.grades.map.iter().any(|m| m.target == name))
+        })
+    {
+        info!("filtered: {:?}", filtered.file_name());
+        let dir_name = filtered.file_name();
+        let Some(student_key) = dir_name.to_str() else {
+            continue;
+        };
+
+        if ctx.pre_repack(&filtered.path(), student_key)? == HookOutcome::Skip {
+            continue;
+        }
+
+        let Some(mut studi) = ctx.grades.collect_student(ctx.grading_table, student_key) else {
+            error!("({student_key}) not found in the grading worksheet. Skipping.");
+            continue;
+        };
+
+        if let Some(feedback) = ctx.grades.feedback_html_for(student_key)? {
+            studi.feedback_comment = feedback;
+        }
+
+        // Write the student's record to the csv
+        if let Some(ref mut writer) = csv_writer {
+            writer.serialize(&studi)?;
+        }
+
+        // New directory name. Should be something like
+        // Name, Vorname_12345678_assignsubmission_file_
+        let dir_new_name = ctx
+            .templates
+            .individual_submission_dir(&studi.name, studi.participant_id())?;
+
+        pack_dir(
+            ctx,
+            &filtered.path(),
+            student_key,
+            &dir_new_name,
+            zip_writer,
+            zip_options,
+        )?;
+    }
+    if let Some(ref mut writer) = csv_writer {
+        writer.flush()?;
+    }
+    zip_writer.flush()?;
+
+    Ok(())
+}
+
+pub fn repack_i2g(
+    ctx: &RepackCtx,
+    zip_writer: &mut zip::ZipWriter<File>,
+    zip_options: &zip::write::FileOptions,
+    mut csv_writer: Option<GradingTableWriter>,
+) -> Result<()> {
+    if csv_writer.is_none() || ctx.grades.source == Source::Autofetch {
+        return Err(KasmError::Validation(
+            "Individual2Group Repacking is not supported for Autofetch workflows".into(),
+        ));
+    }
+
+    // Start packing stuff
+    for filtered in std::fs::read_dir(ctx.unpacked_path)?
+        .filter_map(|entry| entry.ok())
+        // Check that whatever we're packing is a _directory_
+        // and it matches the master regex
+        .filter(|entry| {
+            entry.path().is_dir() && ctx.reg.is_match(entry.file_name().to_str().unwrap_or(""))
+        })
+    {
+        info!("filtered: {:?}", filtered.file_name());
+        let dir_name = filtered.file_name();
+        let Some(group_name) = dir_name.to_str() else {
+            continue;
+        };
+
+        if ctx.pre_repack(&filtered.path(), group_name)? == HookOutcome::Skip {
+            continue;
+        }
+
+        let members = ctx.grades.collect_members(ctx.grading_table, group_name);
+        if members.is_empty() {
+            error!("({group_name}) doesn't have any members. Can't repack. Skipping.");
+            continue;
+        }
+
+        // Fan the team's grade and feedback out to every member
+        let feedback = ctx.grades.feedback_html_for(group_name)?;
+        for mut studi in members {
+            if let Some(ref feedback) = feedback {
+                studi.feedback_comment = feedback.clone();
+            }
+
+            // Write the student's record to the csv
+            if let Some(ref mut writer) = csv_writer {
+                writer.serialize(&studi)?;
+            }
 
             // New directory name. Should be something like
-            // Übungsgruppe AB -- Abgabeteam XY_12345678_assignsubmission_file
-            let dir_new_name: String = format!("{group_name}_{group_id}_assignsubmission_file");
-
-            filtered
-                .path()
-                .read_dir()
-                .unwrap()
-                .filter_map(|f| f.ok())
-                // Match files against the second regex
-                .filter(|f| internal_reg.is_match(f.file_name().to_str().unwrap()))
-                .for_each(|f| {
-                    info!("packing {:?}", f);
-                    // Repack each file
-                    let fname = format!("{}/{}", dir_new_name, f.file_name().to_str().unwrap());
-                    zip_writer.start_file(fname, *zip_options).unwrap();
-                    let bytes = std::fs::read(f.path()).unwrap();
-                    zip_writer.write_all(&bytes).unwrap();
-                });
-        });
+            // Name, Vorname_12345678_assignsubmission_file_
+            let dir_new_name = ctx
+                .templates
+                .individual_submission_dir(&studi.name, studi.participant_id())?;
+
+            pack_dir(
+                ctx,
+                &filtered.path(),
+                group_name,
+                &dir_new_name,
+                zip_writer,
+                zip_options,
+            )?;
+        }
+    }
     if let Some(ref mut writer) = csv_writer {
         writer.flush()?;
     }
@@ -0,0 +1,415 @@
+use std::collections::{hash_map::DefaultHasher, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::args::SimilarityCmd;
+use crate::config::{Grades, MasterCfg, FEEDBACK_FILENAME, POINTS_FILENAME};
+use crate::error::{KasmError, Result};
+use crate::status::print_table;
+use crate::template::Template;
+
+/// Matches shorter than this (in normalized chars) are ignored as noise
+const GRAM: usize = 25;
+/// Fingerprints are picked per window of k-grams. Matches of at least
+/// `GRAM + WINDOW - 1` chars are always found.
+const WINDOW: usize = 20;
+/// Larger files are hardly hand-written, so they're skipped
+const MAX_FILE_SIZE: u64 = 1 << 20;
+/// Matching regions shown per pair
+const MAX_REGIONS: usize = 10;
+
+pub const DEFAULT_THRESHOLD: u32 = 50;
+
+/// Settings of `kasm similarity`
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SimilarityCfg {
+    /// Report pairs sharing at least this many percent of their fingerprints
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<u32>,
+
+    /// Skeleton files (or directories) handed out with the sheets. Code from
+    /// them doesn't count. Relative to kasm.toml, `{sheet}` is the sheet id.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub templates: Vec<Template>,
+}
+
+impl SimilarityCfg {
+    pub fn is_empty(&self) -> bool {
+        *self == SimilarityCfg::default()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for template in &self.templates {
+            template.validate(&["sheet"], &[])?;
+        }
+        Ok(())
+    }
+}
+
+/// A text file, normalized (no whitespace, lower case) and fingerprinted
+struct Document {
+    /// Path relative to the team's directory
+    path: PathBuf,
+    /// Line of every normalized char
+    lines: Vec<u32>,
+    /// (hash, offset) of the k-grams picked by winnowing
+    fingerprints: Vec<(u64, usize)>,
+}
+
+impl Document {
+    fn new(path: PathBuf, text: &str) -> Document {
+        let (chars, lines) = normalize(text);
+        Document {
+            path,
+            lines,
+            fingerprints: winnow(&chars),
+        }
+    }
+
+    /// Lines covered by the k-gram at `offset`
+    fn lines_of(&self, offset: usize) -> (u32, u32) {
+        (self.lines[offset], self.lines[offset + GRAM - 1])
+    }
+}
+
+/// Drops whitespace and case. Returns the chars and their lines.
+fn normalize(text: &str) -> (Vec<char>, Vec<u32>) {
+    let (mut chars, mut lines) = (Vec::new(), Vec::new());
+    let mut line = 1;
+    for c in text.chars() {
+        if c == '\n' {
+            line += 1;
+        }
+        if c.is_whitespace() {
+            continue;
+        }
+        for c in c.to_lowercase() {
+            chars.push(c);
+            lines.push(line);
+        }
+    }
+    (chars, lines)
+}
+
+/// Hashes of all k-grams
+fn gram_hashes(chars: &[char]) -> Vec<u64> {
+    chars
+        .windows(GRAM)
+        .map(|gram| {
+            let mut hasher = DefaultHasher::new();
+            gram.hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect()
+}
+
+/// Picks the (rightmost) minimal hash of every window of k-gram hashes
+/// (Schleimer, Wilkerson, Aiken: "Winnowing", 2003)
+fn winnow(chars: &[char]) -> Vec<(u64, usize)> {
+    let hashes = gram_hashes(chars);
+    let mut picked: Vec<(u64, usize)> = Vec::new();
+    for start in 0..=hashes.len().saturating_sub(WINDOW) {
+        let window = &hashes[start..hashes.len().min(start + WINDOW)];
+        let Some((i, &hash)) = window.iter().enumerate().rev().min_by_key(|(_, &h)| h) else {
+            continue;
+        };
+        if picked.last().map(|&(_, offset)| offset) != Some(start + i) {
+            picked.push((hash, start + i));
+        }
+    }
+    picked
+}
+
+/// Everything a team submitted for a sheet
+struct Submission {
+    sheet_id: String,
+    team: String,
+    documents: Vec<Document>,
+    /// Fingerprint -> (document, offset) of its first occurrence
+    fingerprints: HashMap<u64, (usize, usize)>,
+}
+
+impl Submission {
+    fn label(&self, several_sheets: bool) -> String {
+        if several_sheets {
+            format!("{}/{}", self.sheet_id, self.team)
+        } else {
+            self.team.clone()
+        }
+    }
+}
+
+/// Lines of two documents that share fingerprints
+struct Region {
+    a: (usize, u32, u32),
+    b: (usize, u32, u32),
+}
+
+/// Text files below `dir`, except the ones kasm writes itself
+fn text_files(dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(current) = dirs.pop() {
+        for entry in std::fs::read_dir(&current)
+            .map_err(KasmError::io_at(&current))?
+            .filter_map(|entry| entry.ok())
+        {
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with('.') || name == FEEDBACK_FILENAME || name == POINTS_FILENAME {
+                continue;
+            }
+
+            // Doesn't follow symlinks, they could point anywhere (even at `..`)
+            let file_type = entry.file_type().map_err(KasmError::io_at(&path))?;
+            if file_type.is_symlink() {
+                continue;
+            } else if file_type.is_dir() {
+                dirs.push(path);
+            } else if let Some(text) = read_text(&path)? {
+                let rel = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+                files.push((rel, text));
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+/// The contents of `path` if it looks like text (UTF-8, no NUL bytes)
+fn read_text(path: &Path) -> Result<Option<String>> {
+    let size = std::fs::metadata(path)
+        .map_err(KasmError::io_at(path))?
+        .len();
+    if size > MAX_FILE_SIZE {
+        return Ok(None);
+    }
+
+    let bytes = std::fs::read(path).map_err(KasmError::io_at(path))?;
+    if bytes.contains(&0) {
+        return Ok(None);
+    }
+    Ok(String::from_utf8(bytes).ok())
+}
+
+/// Hashes of every k-gram of the skeleton files of `sheet_id`.
+/// All of them, as submissions might pick others than the template itself.
+/// `--template` paths are relative to the current directory (like any other
+/// command line path), the configured ones to kasm.toml.
+fn template_fingerprints(
+    master: &MasterCfg,
+    cmd: &SimilarityCmd,
+    sheet_id: &str,
+) -> Result<HashSet<u64>> {
+    let master_dir = master.location.parent().unwrap_or(Path::new("."));
+    let mut paths = cmd.templates.clone();
+    for template in &master.similarity.templates {
+        paths.push(master_dir.join(template.render(&[("sheet", sheet_id)])?));
+    }
+
+    let mut fingerprints = HashSet::new();
+    for path in paths {
+        let files = if path.is_dir() {
+            text_files(&path)?
+        } else if path.is_file() {
+            read_text(&path)?
+                .map(|text| vec![(path.clone(), text)])
+                .unwrap_or_default()
+        } else {
+            warn!("template {:?} doesn't exist", path);
+            continue;
+        };
+
+        for (path, text) in files {
+            info!("ignoring code from template {:?}", path);
+            fingerprints.extend(gram_hashes(&normalize(&text).0));
+        }
+    }
+    Ok(fingerprints)
+}
+
+/// Reads and fingerprints every team's submission of `grades`
+fn submissions(
+    master: &MasterCfg,
+    cmd: &SimilarityCmd,
+    grades: &Grades,
+) -> Result<Vec<Submission>> {
+    let unpack_path = grades.location.parent().unwrap_or(Path::new("."));
+    let template = template_fingerprints(master, cmd, &grades.sheet_id)?;
+
+    let mut submissions = Vec::new();
+    for gd in &grades.map {
+        let dir = unpack_path.join(&gd.target);
+        if !dir.is_dir() {
+            continue;
+        }
+
+        let documents: Vec<Document> = text_files(&dir)?
+            .into_iter()
+            .map(|(path, text)| Document::new(path, &text))
+            .collect();
+        let mut fingerprints = HashMap::new();
+        for (i, document) in documents.iter().enumerate() {
+            for &(hash, offset) in &document.fingerprints {
+                if !template.contains(&hash) {
+                    fingerprints.entry(hash).or_insert((i, offset));
+                }
+            }
+        }
+
+        submissions.push(Submission {
+            sheet_id: grades.sheet_id.clone(),
+            team: gd.target.clone(),
+            documents,
+            fingerprints,
+        });
+    }
+    Ok(submissions)
+}
+
+/// Merges the shared fingerprints of `a` and `b` into regions of lines
+fn regions(a: &Submission, b: &Submission) -> Vec<Region> {
+    let mut matches: Vec<Region> = a
+        .fingerprints
+        .iter()
+        .filter_map(|(hash, &(doc_a, offset_a))| {
+            let &(doc_b, offset_b) = b.fingerprints.get(hash)?;
+            let lines_a = a.documents[doc_a].lines_of(offset_a);
+            let lines_b = b.documents[doc_b].lines_of(offset_b);
+            Some(Region {
+                a: (doc_a, lines_a.0, lines_a.1),
+                b: (doc_b, lines_b.0, lines_b.1),
+            })
+        })
+        .collect();
+    matches.sort_by_key(|r| (r.a, r.b));
+
+    let touches =
+        |x: (usize, u32, u32), y: (usize, u32, u32)| x.0 == y.0 && y.1 <= x.2 + 1 && x.1 <= y.2 + 1;
+    let mut merged: Vec<Region> = Vec::new();
+    for m in matches {
+        match merged.last_mut() {
+            Some(last) if touches(last.a, m.a) && touches(last.b, m.b) => {
+                last.a = (last.a.0, last.a.1.min(m.a.1), last.a.2.max(m.a.2));
+                last.b = (last.b.0, last.b.1.min(m.b.1), last.b.2.max(m.b.2));
+            }
+            _ => merged.push(m),
+        }
+    }
+    merged
+}
+
+fn show_lines(submission: &Submission, (doc, from, to): (usize, u32, u32)) -> String {
+    let path = submission.documents[doc].path.display();
+    if from == to {
+        format!("{}:{}", path, from)
+    } else {
+        format!("{}:{}-{}", path, from, to)
+    }
+}
+
+/// Compares the submissions of every pair of teams of `sheets` and reports
+/// the ones sharing more code than the threshold
+pub fn similarity(master: &MasterCfg, cmd: &SimilarityCmd, sheets: &[Grades]) -> Result<()> {
+    let threshold = cmd
+        .threshold
+        .or(master.similarity.threshold)
+        .unwrap_or(DEFAULT_THRESHOLD);
+    if threshold > 100 {
+        return Err(KasmError::Validation(format!(
+            "the threshold is a percentage, {} is too much",
+            threshold
+        )));
+    }
+
+    let mut all = Vec::new();
+    for grades in sheets {
+        all.extend(submissions(master, cmd, grades)?);
+    }
+    let several_sheets = sheets.len() > 1;
+
+    // (percent, a, b, shared fingerprints)
+    let mut suspicious: Vec<(u32, usize, usize, usize)> = Vec::new();
+    for (i, a) in all.iter().enumerate() {
+        for (j, b) in all.iter().enumerate().skip(i + 1) {
+            // A team may reuse its own code in later sheets
+            if a.team == b.team {
+                continue;
+            }
+            let smaller = a.fingerprints.len().min(b.fingerprints.len());
+            if smaller == 0 {
+                continue;
+            }
+
+            let shared = a
+                .fingerprints
+                .keys()
+                .filter(|hash| b.fingerprints.contains_key(hash))
+                .count();
+            let percent = (shared * 100 / smaller) as u32;
+            if shared > 0 && percent >= threshold {
+                suspicious.push((percent, i, j, shared));
+            }
+        }
+    }
+    suspicious.sort_by_key(|&(percent, ..)| std::cmp::Reverse(percent));
+
+    if suspicious.is_empty() {
+        info!(
+            "no pair of the {} submissions shares {}% or more",
+            all.len(),
+            threshold
+        );
+        return Ok(());
+    }
+
+    let header = ["similarity", "team", "team", "shared"];
+    let rows: Vec<Vec<String>> = suspicious
+        .iter()
+        .map(|&(percent, i, j, shared)| {
+            vec![
+                format!("{}%", percent),
+                all[i].label(several_sheets),
+                all[j].label(several_sheets),
+                shared.to_string(),
+            ]
+        })
+        .collect();
+    print_table(&header, &rows);
+
+    for &(_, i, j, _) in &suspicious {
+        let (a, b) = (&all[i], &all[j]);
+        println!();
+        println!(
+            "{} <-> {}",
+            a.label(several_sheets),
+            b.label(several_sheets)
+        );
+        let regions = regions(a, b);
+        for region in regions.iter().take(MAX_REGIONS) {
+            println!(
+                "  {}  ~  {}",
+                show_lines(a, region.a),
+                show_lines(b, region.b)
+            );
+        }
+        if regions.len() > MAX_REGIONS {
+            println!("  ... and {} more", regions.len() - MAX_REGIONS);
+        }
+    }
+
+    println!();
+    println!(
+        "{} suspicious pair(s) among {} submissions (threshold {}%)",
+        suspicious.len(),
+        all.len(),
+        threshold
+    );
+    Ok(())
+}
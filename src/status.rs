@@ -0,0 +1,203 @@
+use log::info;
+
+use crate::config::{Grade, Grades, MasterCfg, Source, UNPACK_CSV_FILENAME};
+use crate::error::{KasmError, Result};
+use crate::gradingtable::GradingTable;
+use crate::repack::RepackFilter;
+
+/// Whether a grade made it to Moodle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PushState {
+    /// Not applicable (csv workflow)
+    NotApplicable,
+    NotPushed,
+    Pushed,
+    /// Pushed, but the grade changed afterwards
+    Outdated,
+}
+
+impl PushState {
+    fn of(grades: &Grades, gd: &Grade) -> PushState {
+        if grades.source == Source::CsvAndZip {
+            return PushState::NotApplicable;
+        }
+        if gd.pushed.is_none() && gd.pushed_members.is_empty() {
+            return PushState::NotPushed;
+        }
+
+        // Teams with members graded differently get pushed member by member
+        let up_to_date = if gd.member_grades.is_empty() && gd.pushed_members.is_empty() {
+            gd.pushed == grades.final_grade(gd)
+        } else {
+            gd.pushed_members == grades.final_member_grades(gd)
+        };
+        if up_to_date {
+            PushState::Pushed
+        } else {
+            PushState::Outdated
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            PushState::NotApplicable => "-",
+            PushState::NotPushed => "no",
+            PushState::Pushed => "yes",
+            PushState::Outdated => "outdated",
+        }
+    }
+}
+
+/// Number of students graded by `gd`
+fn member_count(gd: &Grade, table: Option<&GradingTable>) -> Option<usize> {
+    if let Some(ref members) = gd.members {
+        return Some(members.len());
+    }
+
+    let table = table?;
+    match table
+        .records
+        .iter()
+        .filter(|r| r.group == gd.target)
+        .count()
+    {
+        // Not a team, so it's a single student
+        0 => table
+            .records
+            .iter()
+            .any(|r| r.student_key() == gd.target)
+            .then_some(1),
+        n => Some(n),
+    }
+}
+
+fn format_row<S: AsRef<str>>(cells: &[S], widths: &[usize]) -> String {
+    let cells: Vec<String> = cells
+        .iter()
+        .zip(widths)
+        .map(|(cell, &width)| format!("{:<width$}", cell.as_ref(), width = width))
+        .collect();
+    cells.join("  ").trim_end().to_string()
+}
+
+/// Prints `rows` as a plain text table below `header`
+pub fn print_table<S: AsRef<str>>(header: &[&str], rows: &[Vec<S>]) {
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .filter_map(|row| row.get(i))
+                .map(|cell| cell.as_ref().chars().count())
+                .chain([header[i].chars().count()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let rule: Vec<String> = widths.iter().map(|&w| "-".repeat(w)).collect();
+    println!("{}", format_row(header, &widths));
+    println!("{}", format_row(&rule, &widths));
+    for row in rows {
+        println!("{}", format_row(row, &widths));
+    }
+}
+
+/// Members graded differently than their team, e.g. " [12: 0,00]"
+fn member_grades(gd: &Grade) -> String {
+    if gd.member_grades.is_empty() {
+        return String::new();
+    }
+    let grades: Vec<String> = gd
+        .member_grades
+        .iter()
+        .map(|(member, grade)| format!("{}: {}", member, grade))
+        .collect();
+    format!(" [{}]", grades.join(", "))
+}
+
+/// Prints the grading progress of a sheet. Fails if any team
+/// is still missing a grade (or the grade wasn't pushed yet).
+pub fn status(master: &MasterCfg, grades: &Grades) -> Result<()> {
+    let unpack_path = grades.location.parent().ok_or_else(|| {
+        KasmError::Config(format!("{:?} has no parent directory", grades.location))
+    })?;
+    let filter = RepackFilter::new(master)?;
+
+    let csv_path = unpack_path.join(UNPACK_CSV_FILENAME);
+    let table = match grades.source {
+        Source::CsvAndZip if csv_path.is_file() => Some(GradingTable::from_csv(
+            &csv_path,
+            master.csv_headers.as_ref(),
+        )?),
+        _ => None,
+    };
+
+    let header = [
+        "team", "id", "members", "grade", "late", "feedback", "pushed",
+    ];
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let (mut graded, mut with_feedback, mut pushed) = (0, 0, 0);
+
+    for gd in &grades.map {
+        let team_dir = unpack_path.join(&gd.target);
+        let feedback_files = if team_dir.is_dir() {
+            filter.files(&team_dir)?.len()
+        } else {
+            0
+        };
+        let push_state = PushState::of(grades, gd);
+
+        graded += gd.grade.is_some() as usize;
+        with_feedback += (feedback_files > 0) as usize;
+        pushed += (push_state == PushState::Pushed) as usize;
+
+        rows.push(vec![
+            gd.target.clone(),
+            gd.internal_id.clone().unwrap_or_else(|| "-".into()),
+            member_count(gd, table.as_ref())
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| "-".into()),
+            match (gd.grade, grades.final_grade(gd)) {
+                (None, _) => "missing".into(),
+                (Some(grade), Some(final_grade)) if final_grade != grade => {
+                    format!("{} ({})", final_grade, grade)
+                }
+                (Some(grade), _) => grade.to_string(),
+            } + &member_grades(gd),
+            match (grades.late_by(gd), grades.penalty(gd)) {
+                (None, _) => "-".into(),
+                (Some(late_by), Some(penalty)) if penalty.percent > 0 => {
+                    format!("{} (-{}%)", late_by, penalty.percent)
+                }
+                (Some(late_by), _) => late_by.to_string(),
+            },
+            match feedback_files {
+                0 => "no".into(),
+                n => format!("yes ({})", n),
+            },
+            push_state.label().into(),
+        ]);
+    }
+
+    print_table(&header, &rows);
+
+    let total = grades.map.len();
+    println!();
+    print!(
+        "sheet {}: {}/{} graded, {}/{} with feedback files",
+        grades.sheet_id, graded, total, with_feedback, total
+    );
+    if grades.source == Source::Autofetch {
+        print!(", {}/{} pushed", pushed, total);
+    }
+    println!();
+
+    let complete = graded == total && (grades.source != Source::Autofetch || pushed == total);
+    if complete {
+        info!("all done!");
+        Ok(())
+    } else {
+        Err(KasmError::Incomplete(format!(
+            "sheet {} isn't done yet",
+            grades.sheet_id
+        )))
+    }
+}
@@ -0,0 +1,329 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{KasmError, Result};
+
+/// A piece of a parsed template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part<'a> {
+    Literal(&'a str),
+    /// Escaped brace (`{{` or `}}`)
+    Brace(char),
+    Placeholder(&'a str),
+}
+
+/// A name template with named placeholders, e.g. `feedback_{sheet}_{time}.zip`.
+/// Literal braces are written as `{{` and `}}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Template(String);
+
+impl Template {
+    pub fn new(template: &str) -> Template {
+        Template(template.to_string())
+    }
+
+    fn parse(&self) -> Result<Vec<Part<'_>>> {
+        let mut parts = Vec::new();
+        let mut rest = self.0.as_str();
+
+        while !rest.is_empty() {
+            let Some(pos) = rest.find(['{', '}']) else {
+                parts.push(Part::Literal(rest));
+                break;
+            };
+
+            if pos > 0 {
+                parts.push(Part::Literal(&rest[..pos]));
+            }
+            rest = &rest[pos..];
+
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                parts.push(Part::Brace(rest.chars().next().unwrap()));
+                rest = &rest[2..];
+            } else if rest.starts_with('}') {
+                return Err(KasmError::Validation(format!(
+                    "unmatched '}}' in template {:?}",
+                    self.0
+                )));
+            } else {
+                let end = rest.find('}').ok_or_else(|| {
+                    KasmError::Validation(format!("unclosed '{{' in template {:?}", self.0))
+                })?;
+                let name = &rest[1..end];
+                if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    return Err(KasmError::Validation(format!(
+                        "invalid placeholder {{{}}} in template {:?}",
+                        name, self.0
+                    )));
+                }
+                parts.push(Part::Placeholder(name));
+                rest = &rest[end + 1..];
+            }
+        }
+
+        Ok(parts)
+    }
+
+    /// Checks that the template only uses `allowed` placeholders
+    /// and contains all of the `required` ones
+    pub fn validate(&self, allowed: &[&str], required: &[&str]) -> Result<()> {
+        let placeholders: Vec<&str> = self
+            .parse()?
+            .into_iter()
+            .filter_map(|part| match part {
+                Part::Placeholder(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+
+        if let Some(unknown) = placeholders.iter().find(|p| !allowed.contains(p)) {
+            return Err(KasmError::Validation(format!(
+                "unknown placeholder {{{}}} in template {:?} (allowed: {})",
+                unknown,
+                self.0,
+                allowed.join(", ")
+            )));
+        }
+
+        if let Some(missing) = required.iter().find(|r| !placeholders.contains(r)) {
+            return Err(KasmError::Validation(format!(
+                "template {:?} is missing the placeholder {{{}}}",
+                self.0, missing
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Fills in the placeholders. Placeholders without a value are an error.
+    pub fn render(&self, vars: &[(&str, &str)]) -> Result<String> {
+        let mut out = String::new();
+
+        for part in self.parse()? {
+            match part {
+                Part::Literal(lit) => out.push_str(lit),
+                Part::Brace(brace) => out.push(brace),
+                Part::Placeholder(name) => {
+                    let (_, val) = vars.iter().find(|(k, _)| *k == name).ok_or_else(|| {
+                        KasmError::Validation(format!(
+                            "no value for {{{}}} in template {:?}",
+                            name, self.0
+                        ))
+                    })?;
+                    out.push_str(val);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Naming templates for the files and directories kasm creates
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Templates {
+    /// The unpacked sheet directory. Placeholders: `{sheet}`
+    pub unpack_dir: Template,
+
+    /// The repacked feedback zip. Placeholders: `{sheet}`, `{time}` (required)
+    pub feedback_zip: Template,
+
+    /// The repacked grading worksheet. Placeholders: `{sheet}`, `{time}` (required)
+    pub grades_csv: Template,
+
+    /// A team's directory inside the feedback zip.
+    /// Placeholders: `{group_name}`, `{group_id}`
+    pub group_submission_dir: Template,
+
+    /// A student's directory inside the feedback zip (group -> individual).
+    /// Placeholders: `{group_name}`, `{name}`, `{participant_id}`
+    pub member_submission_dir: Template,
+
+    /// A student's directory inside the feedback zip (individual -> individual/group).
+    /// Placeholders: `{name}`, `{participant_id}`
+    pub individual_submission_dir: Template,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            unpack_dir: Template::new("unpack_{sheet}"),
+            feedback_zip: Template::new("feedback_{sheet}_{time}.zip"),
+            grades_csv: Template::new("grades_{sheet}_{time}.csv"),
+            group_submission_dir: Template::new("{group_name}_{group_id}_assignsubmission_file"),
+            member_submission_dir: Template::new(
+                "{group_name}_{name}_{participant_id}_assignsubmission_file_",
+            ),
+            individual_submission_dir: Template::new(
+                "{name}_{participant_id}_assignsubmission_file_",
+            ),
+        }
+    }
+}
+
+impl Templates {
+    pub fn is_default(&self) -> bool {
+        *self == Templates::default()
+    }
+
+    /// Checks all templates for unknown or missing placeholders
+    pub fn validate(&self) -> Result<()> {
+        self.unpack_dir.validate(&["sheet"], &["sheet"])?;
+        // Without the time, repacking again would overwrite the last repack
+        self.feedback_zip.validate(&["sheet", "time"], &["time"])?;
+        self.grades_csv.validate(&["sheet", "time"], &["time"])?;
+        // Moodle needs the ids to match the feedback to the submissions
+        self.group_submission_dir
+            .validate(&["group_name", "group_id"], &["group_id"])?;
+        self.member_submission_dir.validate(
+            &["group_name", "name", "participant_id"],
+            &["participant_id"],
+        )?;
+        self.individual_submission_dir
+            .validate(&["name", "participant_id"], &["participant_id"])?;
+        Ok(())
+    }
+
+    pub fn unpack_dir(&self, sheet: &str) -> Result<PathBuf> {
+        Ok(self.unpack_dir.render(&[("sheet", sheet)])?.into())
+    }
+
+    pub fn feedback_zip(&self, sheet: &str, time: &str) -> Result<PathBuf> {
+        Ok(self
+            .feedback_zip
+            .render(&[("sheet", sheet), ("time", time)])?
+            .into())
+    }
+
+    pub fn grades_csv(&self, sheet: &str, time: &str) -> Result<PathBuf> {
+        Ok(self
+            .grades_csv
+            .render(&[("sheet", sheet), ("time", time)])?
+            .into())
+    }
+
+    pub fn group_submission_dir(&self, group_name: &str, group_id: &str) -> Result<String> {
+        self.group_submission_dir
+            .render(&[("group_name", group_name), ("group_id", group_id)])
+    }
+
+    pub fn member_submission_dir(
+        &self,
+        group_name: &str,
+        name: &str,
+        participant_id: &str,
+    ) -> Result<String> {
+        self.member_submission_dir.render(&[
+            ("group_name", group_name),
+            ("name", name),
+            ("participant_id", participant_id),
+        ])
+    }
+
+    pub fn individual_submission_dir(&self, name: &str, participant_id: &str) -> Result<String> {
+        self.individual_submission_dir
+            .render(&[("name", name), ("participant_id", participant_id)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_braces() {
+        let t = Template::new("{{{sheet}}}_{{x}}");
+        t.validate(&["sheet"], &["sheet"]).unwrap();
+        assert_eq!(t.render(&[("sheet", "05")]).unwrap(), "{05}_{x}");
+        assert_eq!(Template::new("}}{{").render(&[]).unwrap(), "}{");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        let invalid = [
+            "unpack_{sheet",
+            "unpack_sheet}",
+            "unpack_{}",
+            "unpack_{she et}",
+            "{{sheet}",
+        ];
+        for t in invalid {
+            assert!(
+                Template::new(t).validate(&["sheet"], &[]).is_err(),
+                "{:?} should be invalid",
+                t
+            );
+            assert!(Template::new(t).render(&[("sheet", "05")]).is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_and_missing_placeholders() {
+        let t = Template::new("feedback_{sheet}_{group}.zip");
+        assert!(t.validate(&["sheet", "time"], &[]).is_err());
+        assert!(t.render(&[("sheet", "05")]).is_err());
+
+        let t = Template::new("feedback_{time}.zip");
+        t.validate(&["sheet", "time"], &[]).unwrap();
+        assert!(t.validate(&["sheet", "time"], &["sheet"]).is_err());
+    }
+
+    #[test]
+    fn repack_outputs_need_the_time() {
+        let templates = Templates {
+            feedback_zip: Template::new("feedback_{sheet}.zip"),
+            ..Default::default()
+        };
+        assert!(templates.validate().is_err());
+
+        let templates = Templates {
+            grades_csv: Template::new("grades_{sheet}.csv"),
+            ..Default::default()
+        };
+        assert!(templates.validate().is_err());
+    }
+
+    #[test]
+    fn default_templates() {
+        let templates = Templates::default();
+        templates.validate().unwrap();
+
+        assert_eq!(
+            templates.unpack_dir("05").unwrap(),
+            PathBuf::from("unpack_05")
+        );
+        assert_eq!(
+            templates.feedback_zip("05", "1700000000").unwrap(),
+            PathBuf::from("feedback_05_1700000000.zip")
+        );
+        assert_eq!(
+            templates.grades_csv("05", "1700000000").unwrap(),
+            PathBuf::from("grades_05_1700000000.csv")
+        );
+        assert_eq!(
+            templates
+                .group_submission_dir("Übungsgruppe 01 -- Abgabeteam 07", "4711")
+                .unwrap(),
+            "Übungsgruppe 01 -- Abgabeteam 07_4711_assignsubmission_file"
+        );
+        assert_eq!(
+            templates
+                .member_submission_dir(
+                    "Übungsgruppe 01 -- Abgabeteam 07",
+                    "Max Mustermann",
+                    "12345"
+                )
+                .unwrap(),
+            "Übungsgruppe 01 -- Abgabeteam 07_Max Mustermann_12345_assignsubmission_file_"
+        );
+        assert_eq!(
+            templates
+                .individual_submission_dir("Max Mustermann", "12345")
+                .unwrap(),
+            "Max Mustermann_12345_assignsubmission_file_"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+use crate::config::MasterCfg;
+use crate::error::{KasmError, Result};
+use crate::hooks::shell;
+
+pub const TOKEN_ENV_VAR: &str = "KASM_MOODLE_TOKEN";
+pub const KEYRING_SERVICE_NAME: &str = "kasm-moodle-token";
+
+/// Where to find the Moodle token besides the OS keyring.
+/// Looked up in this order: `KASM_MOODLE_TOKEN`, `file`, `command`, keyring.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TokenConfig {
+    /// File containing (only) the token. Must not be accessible by others.
+    /// Relative paths are relative to kasm.toml, `~/` is the home directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+
+    /// Command printing the token, e.g. `pass show moodle`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+/// Places the token can come from
+#[derive(Clone, Copy, Debug, Display, PartialEq, Eq)]
+pub enum TokenSource {
+    #[strum(serialize = "OS keyring")]
+    Keyring,
+    #[strum(serialize = "token file")]
+    File,
+    #[strum(serialize = "command")]
+    Command,
+    #[strum(serialize = "environment variable (KASM_MOODLE_TOKEN, nothing gets saved)")]
+    Env,
+}
+
+impl TokenConfig {
+    pub fn is_empty(&self) -> bool {
+        self.file.is_none() && self.command.is_none()
+    }
+}
+
+/// Finds the Moodle token. See [`TokenConfig`] for the order.
+pub fn resolve(master: &MasterCfg) -> Result<String> {
+    if let Some(token) = from_env() {
+        debug!("using the moodle token from {}", TOKEN_ENV_VAR);
+        return Ok(token);
+    }
+
+    if let Some(ref file) = master.token.file {
+        debug!("using the moodle token from {:?}", file);
+        return read_file(&token_file_path(master, file));
+    }
+
+    if let Some(ref cmd) = master.token.command {
+        debug!("using the moodle token printed by {:?}", cmd);
+        return run_command(cmd);
+    }
+
+    match keyring_entry()?.get_password() {
+        Ok(token) => Ok(token),
+        Err(keyring::Error::NoEntry) => Err(KasmError::Config(
+            "the moodle token is missing. run setup-fetch first!".into(),
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn from_env() -> Option<String> {
+    std::env::var(TOKEN_ENV_VAR)
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+pub fn keyring_entry() -> Result<keyring::Entry> {
+    Ok(keyring::Entry::new(
+        KEYRING_SERVICE_NAME,
+        &whoami::username(),
+    )?)
+}
+
+/// Resolves `~/` and paths relative to kasm.toml
+pub fn token_file_path(master: &MasterCfg, file: &Path) -> PathBuf {
+    if let (Ok(rest), Some(home)) = (file.strip_prefix("~"), std::env::var_os("HOME")) {
+        return PathBuf::from(home).join(rest);
+    }
+
+    master
+        .location
+        .parent()
+        .map(|dir| dir.join(file))
+        .unwrap_or_else(|| file.to_path_buf())
+}
+
+pub fn read_file(path: &Path) -> Result<String> {
+    check_permissions(path)?;
+    non_empty(
+        std::fs::read_to_string(path).map_err(KasmError::io_at(path))?,
+        &format!("{:?}", path),
+    )
+}
+
+/// Writes `token` into `path`, readable only by the current user
+pub fn write_file(path: &Path, token: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(KasmError::io_at(dir))?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path).map_err(KasmError::io_at(path))?;
+    // The mode only applies to new files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .map_err(KasmError::io_at(path))?;
+    }
+
+    std::io::Write::write_all(&mut file, format!("{}\n", token).as_bytes())
+        .map_err(KasmError::io_at(path))
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)
+        .map_err(KasmError::io_at(path))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(KasmError::Config(format!(
+            "{:?} is accessible by other users (mode {:o}). run chmod 600 on it",
+            path,
+            mode & 0o777
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Runs `cmd` and takes the first line it prints as the token.
+/// stdin/stderr are passed through (e.g. for a GPG passphrase prompt).
+pub fn run_command(cmd: &str) -> Result<String> {
+    let output = shell(cmd)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(KasmError::Config(format!(
+            "token command {:?} failed with {}",
+            cmd, output.status
+        )));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    non_empty(
+        stdout.lines().next().unwrap_or_default().to_string(),
+        &format!("token command {:?}", cmd),
+    )
+}
+
+fn non_empty(token: String, what: &str) -> Result<String> {
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        return Err(KasmError::Config(format!("{} is empty", what)));
+    }
+    Ok(token)
+}
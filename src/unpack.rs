@@ -1,5 +1,6 @@
-use std::collections::HashMap;
-use std::error::Error;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -7,46 +8,54 @@ use crate::args::UnpackFiles;
 use crate::config::Grades;
 use crate::config::MasterCfg;
 
-use crate::gradingtable::GradingRecord;
-use log::{error, info, trace};
+use crate::gradingtable::{GradingRecord, GradingTable};
+use log::{info, trace, warn};
 use regex::Regex;
 
+use crate::config::Extracted;
 use crate::config::Grade;
 use crate::config::Structure;
 use crate::config::UNPACK_CSV_FILENAME;
 use crate::config::UNPACK_GRADES_FILENAME;
-use crate::config::UNPACK_PATH_FILENAME_BASE;
+use crate::error::{KasmError, Result};
+use crate::gradevalue::GradeValue;
+use crate::late::Moment;
 
-pub fn unpack(master: &MasterCfg, cfg: &UnpackFiles) -> Result<(), Box<dyn Error>> {
-    let unpack_path: PathBuf = (UNPACK_PATH_FILENAME_BASE.to_owned() + &cfg.sheet_id).into();
+/// How deep we follow zips inside of zips inside of zips...
+const NESTED_ZIP_MAX_DEPTH: usize = 3;
+/// Maximum number of bytes a single nested zip (including its own
+/// nested zips) may extract to. Guards against zip bombs.
+const NESTED_ZIP_MAX_BYTES: u64 = 512 * 1024 * 1024;
+/// Maximum number of entries a single nested zip may have
+const NESTED_ZIP_MAX_ENTRIES: usize = 10_000;
+
+pub fn unpack(master: &MasterCfg, cfg: &UnpackFiles) -> Result<()> {
+    let unpack_path = master.templates.unpack_dir(&cfg.sheet_id)?;
     if unpack_path.is_dir() {
-        error!("unpack path {:?} already exists!", unpack_path);
-        return Err("".into());
+        return Err(KasmError::Validation(format!(
+            "unpack path {:?} already exists!",
+            unpack_path
+        )));
     }
 
-    info!("creating dir {:?}", unpack_path);
-    std::fs::create_dir_all(unpack_path.clone())?;
-
     let reg = regex::Regex::new(&master.groups_regex)?;
-    let records = GradingRecord::from_csv(&cfg.moodle_csv.clone()).expect("gradingtable csv");
+    let table = GradingTable::from_csv(&cfg.moodle_csv, master.csv_headers.as_ref())?;
 
-    info!("csv has {} records", records.len());
-    let filtered = records
+    info!("csv has {} records", table.records.len());
+    let filtered = table
+        .records
         .iter()
         .filter(|&r| {
-            reg.captures(&r.group).map_or(false, |caps| {
-                caps.get(1)
-                    .map_or(false, |val| val.as_str() == master.group)
-            })
+            reg.captures(&r.group)
+                .is_some_and(|caps| caps.get(1).is_some_and(|val| val.as_str() == master.group))
         })
         .collect::<Vec<_>>();
 
     if filtered.is_empty() {
-        error!(
+        return Err(KasmError::Validation(format!(
             "could not find any records matching master.group = {}",
             master.group
-        );
-        return Err("".into());
+        )));
     }
 
     info!(
@@ -55,8 +64,31 @@ pub fn unpack(master: &MasterCfg, cfg: &UnpackFiles) -> Result<(), Box<dyn Error
         master.group
     );
 
-    let gids = unzip_filter_main(master, cfg, &reg, &unpack_path)?;
-    gen_grading_files(master, cfg, &unpack_path, filtered, &gids)?;
+    info!("creating dir {:?}", unpack_path);
+    std::fs::create_dir_all(unpack_path.clone())?;
+
+    let gids = match master.unpack_structure {
+        Structure::Groups => unzip_filter_main(master, cfg, &reg, &unpack_path)?,
+        Structure::Individuals => {
+            // Regrouping merges the students' submissions into their
+            // teams' directories
+            let targets: HashMap<&str, &str> = filtered
+                .iter()
+                .map(|r| match master.grading_structure() {
+                    Structure::Groups => (r.participant_id(), r.group.as_str()),
+                    Structure::Individuals => (r.participant_id(), r.student_key()),
+                })
+                .collect();
+            unzip_filter_individuals(cfg, &targets, &unpack_path)?;
+            HashMap::new()
+        }
+    };
+    if master.recursive_unzip {
+        unzip_nested_all(&unpack_path)?;
+    }
+
+    let grades = gen_grading_files(master, cfg, &unpack_path, &table, filtered, &gids)?;
+    master.hooks.run_post_unpack(&unpack_path, &grades)?;
 
     Ok(())
 }
@@ -65,60 +97,90 @@ fn gen_grading_files(
     master: &MasterCfg,
     cfg: &UnpackFiles,
     unpack_path: &Path,
+    table: &GradingTable,
     filtered: Vec<&GradingRecord>,
     gids: &HashMap<String, String>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<Grades> {
     let nested_csv_path = unpack_path.join(UNPACK_CSV_FILENAME);
     let mut grades_arr: Vec<Grade> = Vec::new();
     let mut seen: Vec<String> = Vec::new();
-    let nested_csv = std::fs::File::create(nested_csv_path)?;
 
     info!("writing filtered csv");
-    let mut wtr = csv::Writer::from_writer(nested_csv);
-    filtered.iter().for_each(|&r| {
+    let mut wtr = table.writer(&nested_csv_path)?;
+    for &r in filtered.iter() {
         trace!("serializing {:?}", r);
-        wtr.serialize(r).unwrap();
+        wtr.serialize(r)?;
 
         if !seen.contains(&r.group) {
             seen.push(r.group.clone());
 
-            if master.unpack_structure == Structure::Groups {
+            if master.grading_structure() == Structure::Groups {
                 grades_arr.push(Grade {
                     target: r.group.to_owned(),
                     members: None,
                     internal_id: gids.get(&r.group).cloned(),
-                    grade: r.grade.to_owned(),
+                    grade: csv_grade(r),
+                    ..Default::default()
                 })
             }
         }
 
-        if master.unpack_structure == Structure::Individuals {
+        // A team submitted when its last member did
+        let submitted = Moment::parse_moodle(&r.last_change_submission);
+        if let Some(gd) = grades_arr.iter_mut().find(|gd| gd.target == r.group) {
+            gd.submitted = gd.submitted.max(submitted);
+        }
+
+        if master.regroups() {
+            if let Some(gd) = grades_arr.iter_mut().find(|gd| gd.target == r.group) {
+                gd.members
+                    .get_or_insert_with(Vec::new)
+                    .push(r.participant_id().to_owned());
+            }
+        }
+
+        if master.grading_structure() == Structure::Individuals {
             grades_arr.push(Grade {
-                target: r.uni_id.to_owned(),
+                target: r.student_key().to_owned(),
                 members: None,
-                internal_id: r.internal_id.to_owned().into(),
-                grade: r.best_grade.to_owned(),
+                internal_id: r.participant_id().to_owned().into(),
+                grade: csv_grade(r),
+                submitted: Moment::parse_moodle(&r.last_change_submission),
+                ..Default::default()
             });
         }
-    });
+    }
     wtr.flush()?;
 
     info!("saw {} discreet groups", seen.len());
 
     let grades_toml_path = unpack_path.join(UNPACK_GRADES_FILENAME);
+    let grades = Grades {
+        location: grades_toml_path.clone(),
+        map: grades_arr,
+        sheet_id: cfg.sheet_id.to_owned(),
+        max_grade: filtered
+            .first()
+            .and_then(|r| GradeValue::parse_opt(&r.best_grade).ok().flatten()),
+        tasks: Vec::new(),
+        due: cfg.due,
+        late_policy: master.late_policy.clone(),
+        source: crate::config::Source::CsvAndZip,
+        assign_id: None,
+    };
+
     info!("writing grades.toml");
-    std::fs::write(
-        grades_toml_path.clone(),
-        toml::to_string_pretty(&Grades {
-            location: grades_toml_path,
-            map: grades_arr,
-            sheet_id: cfg.sheet_id.to_owned(),
-            source: crate::config::Source::CsvAndZip,
-            assign_id: None,
-        })?,
-    )?;
+    std::fs::write(grades_toml_path, toml::to_string_pretty(&grades)?)?;
 
-    Ok(())
+    Ok(grades)
+}
+
+/// Grade already present in the csv (if any)
+fn csv_grade(r: &GradingRecord) -> Option<GradeValue> {
+    GradeValue::parse_opt(&r.grade).unwrap_or_else(|e| {
+        warn!("ignoring grade of {}: {}", r.name, e);
+        None
+    })
 }
 
 fn unzip_filter_main(
@@ -126,44 +188,360 @@ fn unzip_filter_main(
     cfg: &UnpackFiles,
     reg: &Regex,
     unpack_path: &Path,
-) -> Result<HashMap<String, String>, Box<dyn Error>> {
+) -> Result<HashMap<String, String>> {
     info!("unzipping main zip file");
 
     let mut res = HashMap::<String, String>::new();
-    let file = std::fs::File::open(&cfg.moodle_zip)?;
+    let file = std::fs::File::open(&cfg.moodle_zip).map_err(KasmError::io_at(&cfg.moodle_zip))?;
     let mut archive = zip::ZipArchive::new(file)?;
 
     for i in 0..archive.len() {
         let mut curr = archive.by_index(i)?;
         let curr_name = curr.name();
 
-        if reg.captures(curr_name).map_or(false, |caps| {
-            caps.get(1)
-                .map_or(false, |cap| cap.as_str() == master.group)
-        }) {
-            let enclosed_path = curr.enclosed_name().unwrap();
-            let mut parts = enclosed_path
+        if reg
+            .captures(curr_name)
+            .is_none_or(|caps| caps.get(1).is_none_or(|cap| cap.as_str() != master.group))
+        {
+            continue;
+        }
+
+        let Some(enclosed_path) = curr.enclosed_name().map(|p| p.to_owned()) else {
+            warn!("skipping suspicious path {:?}", curr_name);
+            continue;
+        };
+        let (Some(dirname), Some(filename)) = (
+            enclosed_path
                 .components()
                 .next()
-                .unwrap()
-                .as_os_str()
-                .to_str()
-                .unwrap()
-                .splitn(3, '_');
-            let subdir = parts.next().unwrap();
-            let groupid = parts.next().unwrap();
-
-            res.insert(subdir.to_string(), groupid.to_string());
-
-            let extr = unpack_path
-                .join(subdir)
-                .join(enclosed_path.file_name().unwrap());
-            std::fs::create_dir_all(extr.parent().unwrap())?;
-            let mut target = std::fs::File::create(extr)?;
-
-            std::io::copy(&mut curr, &mut target)?;
+                .and_then(|c| c.as_os_str().to_str()),
+            enclosed_path.file_name(),
+        ) else {
+            continue;
         };
+        let mut parts = dirname.splitn(3, '_');
+        let (Some(subdir), Some(groupid)) = (parts.next(), parts.next()) else {
+            warn!("skipping {:?}: unexpected directory name", enclosed_path);
+            continue;
+        };
+
+        res.insert(subdir.to_string(), groupid.to_string());
+
+        let extr = unpack_path.join(subdir).join(filename);
+        std::fs::create_dir_all(unpack_path.join(subdir))?;
+        let mut target = std::fs::File::create(&extr).map_err(KasmError::io_at(&extr))?;
+
+        std::io::copy(&mut curr, &mut target)?;
     }
 
     Ok(res)
 }
+
+/// Extracts individual submissions into the directories given by `targets`
+/// (participant id -> directory name). Moodle names the folders of individual
+/// submissions like `Name, Vorname_12345678_assignsubmission_file_`, where the
+/// number is the participant id (the numeric part of the csv's "ID" column).
+///
+/// If several students share a directory (regrouping), colliding file names
+/// get suffixed with the participant id of the student who uploaded them.
+fn unzip_filter_individuals(
+    cfg: &UnpackFiles,
+    targets: &HashMap<&str, &str>,
+    unpack_path: &Path,
+) -> Result<()> {
+    info!("unzipping main zip file");
+
+    let mut seen = HashSet::<&str>::new();
+    let file = std::fs::File::open(&cfg.moodle_zip).map_err(KasmError::io_at(&cfg.moodle_zip))?;
+    let mut archive = zip::ZipArchive::new(file)?;
+
+    for i in 0..archive.len() {
+        let mut curr = archive.by_index(i)?;
+        let enclosed_path = match curr.enclosed_name() {
+            Some(path) => path.to_owned(),
+            None => continue,
+        };
+
+        let Some(participant_id) = enclosed_path
+            .components()
+            .next()
+            .and_then(|c| c.as_os_str().to_str())
+            .and_then(participant_id_from_dirname)
+        else {
+            trace!("skipping {:?}", enclosed_path);
+            continue;
+        };
+
+        let Some((&participant_id, &key)) = targets.get_key_value(participant_id.as_str()) else {
+            continue;
+        };
+
+        if curr.is_dir() {
+            continue;
+        }
+
+        seen.insert(participant_id);
+
+        let Some(filename) = enclosed_path.file_name() else {
+            continue;
+        };
+        let mut extr = unpack_path.join(key).join(filename);
+        if extr.exists() {
+            extr = suffixed(&extr, participant_id);
+            warn!("file name collision, extracting to {:?} instead", extr);
+        }
+
+        std::fs::create_dir_all(unpack_path.join(key))?;
+        let mut target = std::fs::File::create(&extr).map_err(KasmError::io_at(&extr))?;
+
+        std::io::copy(&mut curr, &mut target)?;
+    }
+
+    info!("extracted submissions of {} student(s)", seen.len());
+    Ok(())
+}
+
+/// `dir/name.ext` -> `dir/name_suffix.ext`
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, suffix),
+    };
+    path.with_file_name(name)
+}
+
+/// Extracts the participant id out of a folder name like
+/// `Name, Vorname_12345678_assignsubmission_file_`
+fn participant_id_from_dirname(dirname: &str) -> Option<String> {
+    let (prefix, _) = dirname.split_once("_assignsubmission_")?;
+    let (_, participant_id) = prefix.rsplit_once('_')?;
+
+    if participant_id.is_empty() || !participant_id.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(participant_id.to_string())
+}
+
+/// Extracts all zip files found in the team directories of `unpack_path`
+/// (recursively, up to a depth limit) and records the extracted files
+pub fn unzip_nested_all(unpack_path: &Path) -> Result<()> {
+    info!("unzipping nested zip files");
+    let mut extracted = Extracted::read(unpack_path)?;
+
+    for team_dir in std::fs::read_dir(unpack_path)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+    {
+        for zip in std::fs::read_dir(team_dir.path())?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && is_zip(path))
+        {
+            // Don't extract the same archive twice (e.g. when fetching again)
+            if extracted.dir_of(unpack_path, &zip).is_some() {
+                continue;
+            }
+
+            let mut budget = NESTED_ZIP_MAX_BYTES;
+            if let Err(e) = unzip_nested(unpack_path, &zip, 1, &mut budget, &mut extracted) {
+                warn!("could not (fully) extract {:?}: {}", zip, e);
+            }
+        }
+    }
+
+    info!(
+        "extracted {} file(s) from nested zips",
+        extracted.files.len()
+    );
+    extracted.write(unpack_path)
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Picks the directory `archive_path` gets extracted into: named like the
+/// archive without the extension, plus a number if that's taken already
+/// (e.g. by a folder the team submitted next to its zip)
+fn extraction_dir(archive_path: &Path) -> PathBuf {
+    let plain = archive_path.with_extension("");
+    let name = plain.file_name().unwrap_or_default().to_string_lossy();
+
+    std::iter::once(plain.clone())
+        .chain((1..).map(|n| plain.with_file_name(format!("{}_{}", name, n))))
+        .find(|dir| !dir.exists())
+        .unwrap()
+}
+
+/// Extracts `archive_path` into a directory next to it (see
+/// `extraction_dir`). Every extracted file counts against `budget`
+/// and gets recorded in `extracted`.
+fn unzip_nested(
+    unpack_path: &Path,
+    archive_path: &Path,
+    depth: usize,
+    budget: &mut u64,
+    extracted: &mut Extracted,
+) -> Result<()> {
+    if depth > NESTED_ZIP_MAX_DEPTH {
+        warn!(
+            "not extracting {:?}: nested deeper than {} levels",
+            archive_path, NESTED_ZIP_MAX_DEPTH
+        );
+        return Ok(());
+    }
+
+    let target_dir = extraction_dir(archive_path);
+
+    info!("extracting nested zip {:?}", archive_path);
+    let mut archive = zip::ZipArchive::new(File::open(archive_path)?)?;
+    if archive.len() > NESTED_ZIP_MAX_ENTRIES {
+        return Err(KasmError::Validation(format!(
+            "too many entries ({})",
+            archive.len()
+        )));
+    }
+
+    // Record where it went, so that we can tell that we already
+    // extracted this archive
+    if let (Ok(archive), Ok(dir)) = (
+        archive_path.strip_prefix(unpack_path),
+        target_dir.strip_prefix(unpack_path),
+    ) {
+        extracted
+            .archives
+            .insert(archive.to_path_buf(), dir.to_path_buf());
+    }
+
+    for i in 0..archive.len() {
+        let curr = archive.by_index(i)?;
+        let Some(enclosed_path) = curr.enclosed_name().map(|p| p.to_owned()) else {
+            warn!("skipping suspicious path {:?}", curr.name());
+            continue;
+        };
+
+        // macOS metadata is never interesting
+        if curr.is_dir() || enclosed_path.starts_with("__MACOSX") {
+            continue;
+        }
+
+        if curr.size() > *budget {
+            return Err(KasmError::Validation(
+                "exceeds the size limit for nested zips".into(),
+            ));
+        }
+
+        let extr = target_dir.join(enclosed_path);
+        if let Some(parent) = extr.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // Don't trust the size in the header, count what we actually write
+        let written = std::io::copy(&mut curr.take(*budget + 1), &mut File::create(&extr)?)?;
+        if written > *budget {
+            std::fs::remove_file(&extr)?;
+            return Err(KasmError::Validation(
+                "exceeds the size limit for nested zips".into(),
+            ));
+        }
+        *budget -= written;
+
+        trace!("extracted {:?}", extr);
+        if let Ok(rel) = extr.strip_prefix(unpack_path) {
+            extracted.files.push(rel.to_path_buf());
+        }
+
+        if is_zip(&extr) {
+            unzip_nested(unpack_path, &extr, depth + 1, budget, extracted)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_zip(path: &Path, files: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for (name, contents) in files {
+            zip.start_file(*name, Default::default()).unwrap();
+            std::io::Write::write_all(&mut zip, contents).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn nested_zip_doesnt_collide_with_existing_dirs() {
+        let unpack = tempfile::tempdir().unwrap();
+        let team = unpack.path().join("Team A");
+        std::fs::create_dir_all(team.join("abgabe")).unwrap();
+        std::fs::write(team.join("abgabe/notes.txt"), "ours").unwrap();
+        write_zip(&team.join("abgabe.zip"), &[("main.c", b"int main;")]);
+
+        unzip_nested_all(unpack.path()).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(team.join("abgabe/notes.txt")).unwrap(),
+            "ours"
+        );
+        assert!(!team.join("abgabe/main.c").exists());
+        assert_eq!(
+            std::fs::read(team.join("abgabe_1/main.c")).unwrap(),
+            b"int main;"
+        );
+
+        let extracted = Extracted::read(unpack.path()).unwrap();
+        assert_eq!(
+            extracted.dir_of(unpack.path(), &team.join("abgabe.zip")),
+            Some(Path::new("Team A/abgabe_1"))
+        );
+        assert_eq!(extracted.files, [PathBuf::from("Team A/abgabe_1/main.c")]);
+
+        // Already extracted, so nothing happens
+        unzip_nested_all(unpack.path()).unwrap();
+        assert!(!team.join("abgabe_2").exists());
+    }
+
+    #[test]
+    fn participant_id_from_moodle_dirnames() {
+        assert_eq!(
+            participant_id_from_dirname("Mustermann, Max_12345_assignsubmission_file_"),
+            Some("12345".to_string())
+        );
+        // Underscores in the name don't matter
+        assert_eq!(
+            participant_id_from_dirname("Muster_Frau, Erika_678_assignsubmission_onlinetext_"),
+            Some("678".to_string())
+        );
+        // Group submissions carry the group name in front
+        assert_eq!(
+            participant_id_from_dirname(
+                "Übungsgruppe 01 -- Abgabeteam 07_Mustermann, Max_12345_assignsubmission_file_"
+            ),
+            Some("12345".to_string())
+        );
+    }
+
+    #[test]
+    fn participant_id_rejects_other_dirnames() {
+        assert_eq!(participant_id_from_dirname("Mustermann, Max"), None);
+        assert_eq!(
+            participant_id_from_dirname("12345_assignsubmission_file_"),
+            None
+        );
+        assert_eq!(
+            participant_id_from_dirname("Mustermann, Max__assignsubmission_file_"),
+            None
+        );
+        assert_eq!(
+            participant_id_from_dirname("Mustermann, Max_12a45_assignsubmission_file_"),
+            None
+        );
+    }
+}
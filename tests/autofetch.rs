@@ -0,0 +1,491 @@
+//! End-to-end tests of the autofetch workflow against a fake Moodle
+
+mod common;
+
+use common::{Sandbox, COURSE_ID, TEAM, TOKEN};
+use serde_json::json;
+
+/// Repacks sheet 05 and returns the file names in the feedback zip
+fn repacked(sandbox: &Sandbox) -> Vec<String> {
+    sandbox.kasm(&["repack", "05"]).success();
+
+    let zip_path = std::fs::read_dir(sandbox.path())
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "zip"))
+        .expect("repack didn't create a zip");
+    let zip = zip::ZipArchive::new(std::fs::File::open(zip_path).unwrap()).unwrap();
+    zip.file_names().map(String::from).collect()
+}
+
+/// Fetches sheet 05 and returns the team's directory
+fn fetched(sandbox: &Sandbox) -> std::path::PathBuf {
+    sandbox.kasm(&["fetch", "05"]).success();
+    sandbox.sheet_dir("05").join(TEAM)
+}
+
+#[test]
+fn fetch_downloads_own_teams() {
+    let sandbox = Sandbox::new();
+    let team_dir = fetched(&sandbox);
+    let moodle = &sandbox.moodle;
+
+    let assignments = moodle.calls("mod_assign_get_assignments");
+    assert_eq!(assignments.len(), 1);
+    assert_eq!(assignments[0].param("courseids[0]"), Some(COURSE_ID));
+    assert_eq!(assignments[0].param("wstoken"), Some(TOKEN));
+
+    let participants = moodle.calls("mod_assign_list_participants");
+    assert_eq!(participants.len(), 1);
+    assert_eq!(participants[0].param("assignid"), Some("100"));
+
+    let submissions = moodle.calls("mod_assign_get_submissions");
+    assert_eq!(submissions.len(), 1);
+    assert_eq!(submissions[0].param("assignmentids[0]"), Some("100"));
+
+    // Only group 01's files, rebased onto the fake moodle
+    let mut downloads = moodle.downloads();
+    downloads.sort();
+    assert_eq!(downloads, ["loesung.pdf", "main.c"]);
+    assert!(moodle
+        .requests()
+        .iter()
+        .filter(|r| r.path.starts_with("/webservice/pluginfile.php"))
+        .all(|r| r.param("token") == Some(TOKEN)));
+
+    assert_eq!(
+        std::fs::read(team_dir.join("main.c")).unwrap(),
+        std::fs::read(common::fixtures_dir().join("files/main.c")).unwrap()
+    );
+    assert!(!sandbox
+        .sheet_dir("05")
+        .join("Übungsgruppe 02 -- Abgabeteam 03")
+        .exists());
+
+    let grades = sandbox.grades_toml("05");
+    assert_eq!(grades["assign_id"].as_str(), Some("100"));
+    assert_eq!(grades["source"].as_str(), Some("Autofetch"));
+    assert_eq!(grades["max_grade"].as_str(), Some("20,00"));
+    let map = grades["map"].as_array().unwrap();
+    assert_eq!(map.len(), 1);
+    assert_eq!(map[0]["target"].as_str(), Some(TEAM));
+    assert_eq!(map[0]["internal_id"].as_str(), Some("7"));
+    assert_eq!(
+        map[0]["members"],
+        toml::Value::try_from(["11", "12"]).unwrap()
+    );
+}
+
+#[test]
+fn fetch_selects_by_id_and_pattern() {
+    let sandbox = Sandbox::new();
+
+    sandbox.kasm(&["fetch", "--assign-id", "100"]).success();
+    assert!(sandbox.sheet_dir("05").join("grades.toml").is_file());
+
+    // "Blatt 06" and "Bonusblatt 06" are both sheet 06
+    sandbox.kasm(&["fetch", "06"]).exits_with(15);
+    sandbox
+        .kasm(&["fetch", "--assign-id", "999"])
+        .exits_with(15);
+    sandbox.kasm(&["fetch", "--match", "^Nope"]).exits_with(15);
+}
+
+#[test]
+fn fetch_lists_assignments() {
+    let sandbox = Sandbox::new();
+
+    let run = sandbox.kasm(&["fetch", "--list", "--json"]);
+    run.success();
+    let listed: serde_json::Value = serde_json::from_str(&run.stdout()).unwrap();
+    assert_eq!(
+        listed[0],
+        json!({ "id": 100, "sheet": "05", "name": "Blatt 05", "max_grade": "20,00" })
+    );
+    assert_eq!(listed.as_array().unwrap().len(), 3);
+    assert!(sandbox.moodle.downloads().is_empty());
+}
+
+#[test]
+fn fetch_again_only_downloads_changes() {
+    let sandbox = Sandbox::new();
+    let team_dir = fetched(&sandbox);
+    let grades_toml = sandbox.sheet_dir("05").join("grades.toml");
+
+    // A grade and a feedback file the tutor created in the meantime
+    sandbox.kasm_in(&team_dir, &["grade", "12"]).success();
+    std::fs::write(team_dir.join("feedback.md"), "gut").unwrap();
+
+    sandbox.moodle.clear_requests();
+    sandbox.kasm(&["fetch", "05"]).success();
+    assert!(sandbox.moodle.downloads().is_empty());
+
+    // A late upload replaces main.c
+    let mut submissions = common::FakeMoodle::fixture("mod_assign_get_submissions");
+    let main_c = &mut submissions["assignments"][0]["submissions"][0]["plugins"][0]["fileareas"][0]
+        ["files"][1];
+    main_c["filesize"] = json!(9);
+    main_c["timemodified"] = json!(1700000100);
+    sandbox
+        .moodle
+        .respond("mod_assign_get_submissions", submissions);
+    sandbox.moodle.serve_file("main.c", b"int x;\n\n\n");
+
+    sandbox.moodle.clear_requests();
+    sandbox.kasm(&["fetch", "05"]).success();
+    assert_eq!(sandbox.moodle.downloads(), ["main.c"]);
+    assert_eq!(
+        std::fs::read(team_dir.join("main.c")).unwrap(),
+        b"int x;\n\n\n"
+    );
+
+    assert_eq!(
+        std::fs::read_to_string(team_dir.join("feedback.md")).unwrap(),
+        "gut"
+    );
+    assert!(std::fs::read_to_string(grades_toml)
+        .unwrap()
+        .contains("grade = \"12,00\""));
+}
+
+/// A zip containing `files` (name, contents)
+fn zip_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, contents) in files {
+        zip.start_file(*name, Default::default()).unwrap();
+        std::io::Write::write_all(&mut zip, contents).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+/// Makes the team submit `abgabe.zip` (instead of main.c)
+fn submit_zip(sandbox: &Sandbox, zip: &[u8], timemodified: i64) {
+    let mut submissions = common::FakeMoodle::fixture("mod_assign_get_submissions");
+    let file = &mut submissions["assignments"][0]["submissions"][0]["plugins"][0]["fileareas"][0]
+        ["files"][1];
+    file["filename"] = json!("abgabe.zip");
+    file["fileurl"] = json!("https://moodle.example.org/webservice/pluginfile.php/1/assignsubmission_file/submission_files/5/abgabe.zip");
+    file["filesize"] = json!(zip.len());
+    file["timemodified"] = json!(timemodified);
+    sandbox
+        .moodle
+        .respond("mod_assign_get_submissions", submissions);
+    sandbox.moodle.serve_file("abgabe.zip", zip);
+}
+
+#[test]
+fn fetch_again_extracts_updated_zips() {
+    let sandbox = Sandbox::new();
+    let kasm_toml = sandbox.path().join("kasm.toml");
+    let master = std::fs::read_to_string(&kasm_toml).unwrap();
+    std::fs::write(
+        &kasm_toml,
+        master.replace("recursive_unzip = false", "recursive_unzip = true"),
+    )
+    .unwrap();
+
+    let inner = zip_of(&[("a.c", b"v1")]);
+    submit_zip(
+        &sandbox,
+        &zip_of(&[("top.c", b"v1"), ("old.c", b"v1"), ("inner.zip", &inner)]),
+        1700000000,
+    );
+    let team_dir = fetched(&sandbox);
+    let extracted = team_dir.join("abgabe");
+    assert_eq!(std::fs::read(extracted.join("top.c")).unwrap(), b"v1");
+    assert_eq!(std::fs::read(extracted.join("inner/a.c")).unwrap(), b"v1");
+
+    // The team uploads a new version, with an updated inner zip
+    let inner = zip_of(&[("a.c", b"v2")]);
+    submit_zip(
+        &sandbox,
+        &zip_of(&[("top.c", b"v2"), ("inner.zip", &inner)]),
+        1700000100,
+    );
+    sandbox.moodle.clear_requests();
+    let run = sandbox.kasm(&["fetch", "05"]);
+    run.success();
+    assert_eq!(sandbox.moodle.downloads(), ["abgabe.zip"]);
+    assert!(!run.stderr().contains("could not"), "{}", run.stderr());
+
+    assert_eq!(std::fs::read(extracted.join("top.c")).unwrap(), b"v2");
+    assert_eq!(std::fs::read(extracted.join("inner/a.c")).unwrap(), b"v2");
+    assert!(!extracted.join("old.c").exists());
+    assert!(!team_dir.join("abgabe_1").exists());
+
+    // Only the files directly in the team's directory get shipped
+    let files = repacked(&sandbox);
+    assert!(
+        files.iter().any(|f| f.ends_with("/abgabe.zip")),
+        "{:?}",
+        files
+    );
+    assert!(!files.iter().any(|f| f.ends_with(".c")), "{:?}", files);
+}
+
+#[test]
+fn fetch_reports_failed_downloads() {
+    let sandbox = Sandbox::new();
+    let mut submissions = common::FakeMoodle::fixture("mod_assign_get_submissions");
+    submissions["assignments"][0]["submissions"][0]["plugins"][0]["fileareas"][0]["files"][0]
+        ["fileurl"] = json!("https://moodle.example.org/webservice/pluginfile.php/1/gone.pdf");
+    sandbox
+        .moodle
+        .respond("mod_assign_get_submissions", submissions);
+
+    sandbox.kasm(&["fetch", "05"]).exits_with(1);
+    let team_dir = sandbox.sheet_dir("05").join(TEAM);
+    assert!(team_dir.join("main.c").is_file());
+    assert!(!team_dir.join("loesung.pdf").exists());
+}
+
+#[test]
+fn invalid_token_is_a_moodle_error() {
+    let sandbox = Sandbox::new();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_kasm"))
+        .args(["fetch", "--list"])
+        .current_dir(sandbox.path())
+        .env("KASM_MOODLE_TOKEN", "wrong")
+        .env("HOME", sandbox.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(14));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalidtoken"));
+}
+
+#[test]
+fn push_sends_grades_and_feedback() {
+    let sandbox = Sandbox::new();
+    let team_dir = fetched(&sandbox);
+
+    sandbox
+        .kasm_in(&team_dir, &["grade", "15,5", "-m", "**gut**"])
+        .success();
+    std::fs::write(team_dir.join("korrektur.pdf"), "%PDF korrektur").unwrap();
+
+    sandbox.moodle.clear_requests();
+    sandbox.kasm_in(&team_dir, &["push", "--files"]).success();
+
+    let saved = sandbox.moodle.calls("mod_assign_save_grade");
+    assert_eq!(saved.len(), 1);
+    let saved = &saved[0];
+    assert_eq!(saved.param("assignmentid"), Some("100"));
+    assert_eq!(saved.param("userid"), Some("11"));
+    assert_eq!(saved.param("grade"), Some("15.50"));
+    assert_eq!(saved.param("applytoall"), Some("1"));
+    assert!(saved
+        .param("plugindata[assignfeedbackcomments_editor][text]")
+        .unwrap()
+        .contains("<strong>gut</strong>"));
+    assert_eq!(saved.param("plugindata[files_filemanager]"), Some("4242"));
+
+    let uploads = sandbox.moodle.uploads();
+    assert_eq!(uploads.len(), 1);
+    assert!(uploads[0]
+        .uploaded_filenames()
+        .contains(&"korrektur.pdf".to_string()));
+
+    let grades = sandbox.grades_toml("05");
+    assert_eq!(grades["map"][0]["pushed"].as_str(), Some("15,50"));
+    sandbox.kasm_in(&team_dir, &["status"]).success();
+}
+
+#[test]
+fn push_applies_late_penalty() {
+    let sandbox = Sandbox::new();
+    let kasm_toml = sandbox.path().join("kasm.toml");
+    let mut master = std::fs::read_to_string(&kasm_toml).unwrap();
+    master.push_str("\n[late_policy]\ngrace = \"15m\"\npercent_per_day = 10\n");
+    std::fs::write(&kasm_toml, master).unwrap();
+
+    // Submitted 6 hours after the due date
+    let mut assignments = common::FakeMoodle::fixture("mod_assign_get_assignments");
+    assignments["courses"][0]["assignments"][0]["duedate"] = json!(1700000000);
+    sandbox
+        .moodle
+        .respond("mod_assign_get_assignments", assignments);
+    let mut submissions = common::FakeMoodle::fixture("mod_assign_get_submissions");
+    submissions["assignments"][0]["submissions"][0]["timemodified"] = json!(1700021600);
+    sandbox
+        .moodle
+        .respond("mod_assign_get_submissions", submissions);
+
+    let team_dir = fetched(&sandbox);
+    let grades = sandbox.grades_toml("05");
+    assert_eq!(grades["due"].as_str(), Some("2023-11-14 22:13:20"));
+    assert_eq!(
+        grades["map"][0]["submitted"].as_str(),
+        Some("2023-11-15 04:13:20")
+    );
+
+    sandbox.kasm_in(&team_dir, &["grade", "15"]).success();
+    sandbox.moodle.clear_requests();
+    sandbox.kasm_in(&team_dir, &["push"]).success();
+
+    let saved = sandbox.moodle.calls("mod_assign_save_grade");
+    assert_eq!(saved[0].param("grade"), Some("13.50"));
+    assert!(saved[0]
+        .param("plugindata[assignfeedbackcomments_editor][text]")
+        .unwrap()
+        .contains("submitted 6h late: -10%"));
+    assert_eq!(
+        sandbox.grades_toml("05")["map"][0]["grade"].as_str(),
+        Some("15,00")
+    );
+}
+
+#[test]
+fn push_grades_members_individually() {
+    let sandbox = Sandbox::new();
+    let team_dir = fetched(&sandbox);
+
+    sandbox.kasm_in(&team_dir, &["grade", "16"]).success();
+    sandbox
+        .kasm_in(
+            &team_dir,
+            &["grade", "0", "--member", "12", "-m", "nicht mitgearbeitet"],
+        )
+        .success();
+    sandbox
+        .kasm_in(&team_dir, &["grade", "5", "--member", "13"])
+        .exits_with(15);
+    let grades = sandbox.grades_toml("05");
+    assert_eq!(grades["map"][0]["grade"].as_str(), Some("16,00"));
+    assert_eq!(
+        grades["map"][0]["member_grades"]["12"].as_str(),
+        Some("0,00")
+    );
+    assert_eq!(
+        grades["map"][0]["feedback"].as_str(),
+        Some("nicht mitgearbeitet")
+    );
+
+    sandbox.moodle.clear_requests();
+    sandbox.kasm_in(&team_dir, &["push"]).success();
+
+    let saved = sandbox.moodle.calls("mod_assign_save_grade");
+    let pushed: Vec<_> = saved
+        .iter()
+        .map(|r| {
+            (
+                r.param("userid").unwrap(),
+                r.param("grade").unwrap(),
+                r.param("applytoall").unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(pushed, [("11", "16.00", "0"), ("12", "0.00", "0")]);
+
+    let run = sandbox.kasm_in(&team_dir, &["diff"]);
+    run.success();
+    assert!(run.stdout().contains(&format!("{} (12)", TEAM)));
+
+    let grades = sandbox.grades_toml("05");
+    assert_eq!(
+        grades["map"][0]["pushed_members"]["12"].as_str(),
+        Some("0,00")
+    );
+    sandbox.kasm_in(&team_dir, &["status"]).success();
+
+    // Changing an override after the push needs another push
+    sandbox
+        .kasm_in(&team_dir, &["grade", "2", "--member", "12"])
+        .success();
+    let run = sandbox.kasm_in(&team_dir, &["status"]);
+    run.exits_with(1);
+    assert!(run.stdout().contains("outdated"), "{}", run.stdout());
+}
+
+#[test]
+fn push_dry_run_saves_nothing() {
+    let sandbox = Sandbox::new();
+    let team_dir = fetched(&sandbox);
+    sandbox.kasm_in(&team_dir, &["grade", "10"]).success();
+
+    sandbox.moodle.clear_requests();
+    sandbox.kasm_in(&team_dir, &["push", "--dry-run"]).success();
+    assert!(sandbox.moodle.calls("mod_assign_save_grade").is_empty());
+    assert_eq!(sandbox.moodle.calls("mod_assign_get_participant").len(), 1);
+    assert!(sandbox.grades_toml("05")["map"][0].get("pushed").is_none());
+}
+
+#[test]
+fn push_validates_before_sending() {
+    let sandbox = Sandbox::new();
+    fetched(&sandbox);
+
+    // More than the assignment's 20 points
+    let path = sandbox.sheet_dir("05").join("grades.toml");
+    let grades = std::fs::read_to_string(&path)
+        .unwrap()
+        .replace("grade = \"\"", "grade = \"21\"");
+    std::fs::write(&path, grades).unwrap();
+
+    sandbox.moodle.clear_requests();
+    sandbox
+        .kasm_in(&sandbox.sheet_dir("05"), &["push"])
+        .exits_with(15);
+    assert!(sandbox.moodle.calls("mod_assign_save_grade").is_empty());
+}
+
+#[test]
+fn diff_compares_with_moodle() {
+    let sandbox = Sandbox::new();
+    let team_dir = fetched(&sandbox);
+    sandbox.kasm_in(&team_dir, &["grade", "12,5"]).success();
+
+    let run = sandbox.kasm_in(&team_dir, &["diff"]);
+    run.success();
+    assert_eq!(sandbox.moodle.calls("mod_assign_get_grades").len(), 1);
+    assert!(run.stdout().contains("changed"));
+    assert!(run.stdout().contains("10,00"));
+    assert!(run.stdout().contains("12,50"));
+}
+
+#[test]
+fn repack_packs_fetched_sheet() {
+    let sandbox = Sandbox::new();
+    let team_dir = fetched(&sandbox);
+    sandbox.kasm_in(&team_dir, &["grade", "20"]).success();
+    std::fs::write(team_dir.join("feedback.md"), "super").unwrap();
+
+    let names = repacked(&sandbox);
+    assert!(
+        names
+            .iter()
+            .any(|name| name.starts_with(&format!("{}_5011_assignsubmission_file/", TEAM))),
+        "{:?}",
+        names
+    );
+}
+
+#[test]
+fn repack_hands_feedback_to_members() {
+    let sandbox = Sandbox::new();
+    let kasm_toml = sandbox.path().join("kasm.toml");
+    let master = std::fs::read_to_string(&kasm_toml).unwrap().replace(
+        "repack_structure = \"Groups\"",
+        "repack_structure = \"Individuals\"",
+    );
+    std::fs::write(&kasm_toml, master).unwrap();
+
+    let team_dir = fetched(&sandbox);
+    let grades = sandbox.grades_toml("05");
+    let participants = &grades["map"][0]["participants"];
+    assert_eq!(participants["11"]["name"].as_str(), Some("Anna A"));
+    assert_eq!(participants["12"]["participant_id"].as_str(), Some("5012"));
+
+    std::fs::write(team_dir.join("korrektur.pdf"), "%PDF korrektur").unwrap();
+    let mut names = repacked(&sandbox);
+    names.retain(|name| name.ends_with("korrektur.pdf"));
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            format!("{}_Anna A_5011_assignsubmission_file_/korrektur.pdf", TEAM),
+            format!("{}_Bob B_5012_assignsubmission_file_/korrektur.pdf", TEAM),
+        ]
+    );
+}
@@ -0,0 +1,427 @@
+//! A fake Moodle web service plus helpers to run kasm against it.
+//!
+//! The server answers REST calls with the JSON fixtures in
+//! `tests/fixtures/moodle` (see [`FakeMoodle::fixture`]), serves the files in
+//! `tests/fixtures/moodle/files` via pluginfile.php and records every request.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use serde_json::{json, Value};
+
+pub const TOKEN: &str = "test-token";
+pub const COURSE_ID: &str = "1";
+pub const TEAM: &str = "Übungsgruppe 01 -- Abgabeteam 01";
+
+const REST_PATH: &str = "/webservice/rest/server.php";
+const UPLOAD_PATH: &str = "/webservice/upload.php";
+const PLUGINFILE_PATH: &str = "/webservice/pluginfile.php";
+
+pub fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/moodle")
+}
+
+/// A request the fake Moodle received
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Query and (urlencoded) form parameters
+    pub params: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn wsfunction(&self) -> Option<&str> {
+        self.param("wsfunction")
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|s| s.as_str())
+    }
+
+    /// Names of the files in a multipart body
+    pub fn uploaded_filenames(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.body)
+            .split("filename=\"")
+            .skip(1)
+            .filter_map(|part| part.split('"').next())
+            .map(|name| name.to_string())
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    requests: Vec<Request>,
+    /// wsfunction -> answer, overriding the fixture
+    overrides: HashMap<String, Value>,
+    /// file name -> contents, overriding tests/fixtures/moodle/files
+    files: HashMap<String, Vec<u8>>,
+}
+
+pub struct FakeMoodle {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl FakeMoodle {
+    pub fn start() -> FakeMoodle {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind the fake moodle");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let (thread_state, thread_shutdown) = (state.clone(), shutdown.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else { continue };
+                let state = thread_state.clone();
+                thread::spawn(move || handle(stream, &state));
+            }
+        });
+
+        FakeMoodle {
+            url,
+            state,
+            shutdown,
+        }
+    }
+
+    /// Answers `wsfunction` with `answer` instead of its fixture
+    pub fn respond(&self, wsfunction: &str, answer: Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .overrides
+            .insert(wsfunction.into(), answer);
+    }
+
+    /// Serves `contents` as the file `name` instead of its fixture
+    pub fn serve_file(&self, name: &str, contents: &[u8]) {
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .insert(name.into(), contents.to_vec());
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// All calls of `wsfunction`
+    pub fn calls(&self, wsfunction: &str) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.wsfunction() == Some(wsfunction))
+            .collect()
+    }
+
+    /// All pluginfile.php downloads (the file names)
+    pub fn downloads(&self) -> Vec<String> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.path.starts_with(PLUGINFILE_PATH))
+            .filter_map(|r| r.path.rsplit('/').next().map(|name| name.to_string()))
+            .collect()
+    }
+
+    pub fn uploads(&self) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.path == UPLOAD_PATH)
+            .collect()
+    }
+
+    pub fn clear_requests(&self) {
+        self.state.lock().unwrap().requests.clear();
+    }
+
+    /// The fixture answer for `wsfunction`
+    pub fn fixture(wsfunction: &str) -> Value {
+        let name = match wsfunction {
+            "core_webservice_get_site_info" => "site_info",
+            "mod_assign_get_assignments" => "get_assignments",
+            "mod_assign_list_participants" => "list_participants",
+            "mod_assign_get_submissions" => "get_submissions",
+            "mod_assign_get_participant" => "get_participant",
+            "mod_assign_get_grades" => "get_grades",
+            "mod_assign_save_grade" => return Value::Null,
+            _ => {
+                return exception(
+                    "invalidfunction",
+                    &format!("{} is not available", wsfunction),
+                )
+            }
+        };
+
+        let path = fixtures_dir().join(format!("{}.json", name));
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap())
+            .unwrap_or_else(|e| panic!("{:?} is broken: {}", path, e))
+    }
+}
+
+impl Drop for FakeMoodle {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake up the accept loop
+        let _ = TcpStream::connect(self.url.trim_start_matches("http://"));
+    }
+}
+
+fn exception(errorcode: &str, message: &str) -> Value {
+    json!({
+        "exception": "moodle_exception",
+        "errorcode": errorcode,
+        "message": message,
+    })
+}
+
+fn handle(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let Some(request) = read_request(&mut reader) else {
+        return;
+    };
+
+    let (status, content_type, body) = answer(&request, state);
+    state.lock().unwrap().requests.push(request);
+
+    let mut stream = stream;
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    let _ = stream.write_all(&body);
+}
+
+fn answer(request: &Request, state: &Mutex<State>) -> (&'static str, &'static str, Vec<u8>) {
+    const JSON: &str = "application/json";
+    let json = |value: &Value| ("200 OK", JSON, value.to_string().into_bytes());
+
+    if request.path == REST_PATH {
+        if request.param("wstoken") != Some(TOKEN) {
+            return json(&exception(
+                "invalidtoken",
+                "Invalid token - token not found",
+            ));
+        }
+        let wsfunction = request.wsfunction().unwrap_or_default();
+        let answer = state.lock().unwrap().overrides.get(wsfunction).cloned();
+        return json(&answer.unwrap_or_else(|| FakeMoodle::fixture(wsfunction)));
+    }
+
+    if request.path == UPLOAD_PATH {
+        let uploaded: Vec<Value> = request
+            .uploaded_filenames()
+            .iter()
+            .map(|name| json!({ "itemid": 4242, "filename": name }))
+            .collect();
+        return json(&Value::Array(uploaded));
+    }
+
+    if request.path.starts_with(PLUGINFILE_PATH) {
+        if request.param("token") != Some(TOKEN) {
+            return ("403 Forbidden", "text/plain", b"forbidden".to_vec());
+        }
+        let name = request.path.rsplit('/').next().unwrap_or_default();
+        let contents = state.lock().unwrap().files.get(name).cloned();
+        return match contents
+            .or_else(|| std::fs::read(fixtures_dir().join("files").join(name)).ok())
+        {
+            Some(contents) => ("200 OK", "application/octet-stream", contents),
+            None => ("404 Not Found", "text/plain", b"not found".to_vec()),
+        };
+    }
+
+    ("404 Not Found", "text/plain", b"not found".to_vec())
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let body = if headers
+        .get("transfer-encoding")
+        .is_some_and(|te| te.eq_ignore_ascii_case("chunked"))
+    {
+        read_chunked(reader)?
+    } else {
+        let len: usize = headers
+            .get("content-length")
+            .and_then(|len| len.parse().ok())
+            .unwrap_or(0);
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).ok()?;
+        body
+    };
+
+    let url = reqwest::Url::parse(&format!("http://fake{}", target)).ok()?;
+    let mut params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    if headers
+        .get("content-type")
+        .is_some_and(|ct| ct.starts_with("application/x-www-form-urlencoded"))
+    {
+        let form = reqwest::Url::parse(&format!("http://fake/?{}", String::from_utf8_lossy(&body)))
+            .ok()?;
+        params.extend(form.query_pairs().into_owned());
+    }
+
+    Some(Request {
+        method,
+        path: url.path().to_string(),
+        params,
+        body,
+    })
+}
+
+fn read_chunked(reader: &mut BufReader<TcpStream>) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut size = String::new();
+        reader.read_line(&mut size).ok()?;
+        let size = usize::from_str_radix(size.trim(), 16).ok()?;
+        let mut chunk = vec![0; size + 2];
+        reader.read_exact(&mut chunk).ok()?;
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(&chunk[..size]);
+    }
+}
+
+/// A master directory (with kasm.toml) pointing at a fake Moodle
+pub struct Sandbox {
+    pub dir: tempfile::TempDir,
+    pub moodle: FakeMoodle,
+}
+
+impl Sandbox {
+    pub fn new() -> Sandbox {
+        let sandbox = Sandbox {
+            dir: tempfile::tempdir().unwrap(),
+            moodle: FakeMoodle::start(),
+        };
+
+        let url = sandbox.moodle.url.clone();
+        sandbox
+            .kasm(&[
+                "init",
+                "-g",
+                "01",
+                "--course-id",
+                COURSE_ID,
+                "--moodle-url",
+                &url,
+            ])
+            .success();
+        sandbox
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// The unpack directory of `sheet`
+    pub fn sheet_dir(&self, sheet: &str) -> PathBuf {
+        self.path().join(format!("unpack_{}", sheet))
+    }
+
+    pub fn grades_toml(&self, sheet: &str) -> toml::Value {
+        let path = self.sheet_dir(sheet).join("grades.toml");
+        toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap()
+    }
+
+    /// Runs kasm in the master directory
+    pub fn kasm(&self, args: &[&str]) -> Run {
+        self.kasm_in(self.path(), args)
+    }
+
+    /// Runs kasm in `dir`
+    pub fn kasm_in(&self, dir: &Path, args: &[&str]) -> Run {
+        let output = Command::new(env!("CARGO_BIN_EXE_kasm"))
+            .args(args)
+            .current_dir(dir)
+            .env("KASM_MOODLE_TOKEN", TOKEN)
+            .env("HOME", self.path())
+            .env("RUST_LOG", "info")
+            .output()
+            .expect("could not run kasm");
+        Run {
+            args: args.iter().map(|a| a.to_string()).collect(),
+            output,
+        }
+    }
+}
+
+/// A finished kasm run
+pub struct Run {
+    args: Vec<String>,
+    pub output: Output,
+}
+
+impl Run {
+    pub fn code(&self) -> Option<i32> {
+        self.output.status.code()
+    }
+
+    pub fn stdout(&self) -> String {
+        String::from_utf8_lossy(&self.output.stdout).into_owned()
+    }
+
+    pub fn stderr(&self) -> String {
+        String::from_utf8_lossy(&self.output.stderr).into_owned()
+    }
+
+    /// Panics (showing kasm's output) unless kasm exited with `code`
+    pub fn exits_with(&self, code: i32) -> &Run {
+        assert_eq!(
+            self.code(),
+            Some(code),
+            "kasm {:?}\n--- stdout\n{}\n--- stderr\n{}",
+            self.args,
+            self.stdout(),
+            self.stderr()
+        );
+        self
+    }
+
+    pub fn success(&self) -> &Run {
+        self.exits_with(0)
+    }
+}
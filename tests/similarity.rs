@@ -0,0 +1,125 @@
+//! Tests of `kasm similarity` on a hand-made sheet
+
+mod common;
+
+use common::Sandbox;
+
+/// Handed out with the sheet, so every team has it
+const SKELETON: &str = r#"#include <stdio.h>
+#include <stdlib.h>
+
+/* Aufgabe 3: Implementieren Sie die Funktion sort_numbers,
+ * die ein Array von Ganzzahlen aufsteigend sortiert. */
+void sort_numbers(int *numbers, size_t count);
+"#;
+
+const SOLUTION: &str = r#"
+void sort_numbers(int *numbers, size_t count) {
+    for (size_t i = 1; i < count; i++) {
+        int current = numbers[i];
+        size_t j = i;
+        while (j > 0 && numbers[j - 1] > current) {
+            numbers[j] = numbers[j - 1];
+            j--;
+        }
+        numbers[j] = current;
+    }
+}
+"#;
+
+const OTHER_SOLUTION: &str = r#"
+static int compare(const void *a, const void *b) {
+    return (*(const int *)a > *(const int *)b) - (*(const int *)a < *(const int *)b);
+}
+
+void sort_numbers(int *numbers, size_t count) {
+    qsort(numbers, count, sizeof *numbers, compare);
+}
+"#;
+
+/// Sheet 05 with three teams: B copied A (reformatted), C didn't
+fn sheet(sandbox: &Sandbox) {
+    let sheet_dir = sandbox.sheet_dir("05");
+    let teams = [
+        ("Team A", format!("{}{}", SKELETON, SOLUTION)),
+        (
+            "Team B",
+            format!(
+                "{}{}",
+                SKELETON,
+                SOLUTION.replace("    ", "\t").replace("current", "CURRENT")
+            ),
+        ),
+        ("Team C", format!("{}{}", SKELETON, OTHER_SOLUTION)),
+    ];
+
+    let mut grades = String::from("sheet_id = \"05\"\nsource = \"CsvAndZip\"\n");
+    for (team, code) in teams {
+        std::fs::create_dir_all(sheet_dir.join(team)).unwrap();
+        std::fs::write(sheet_dir.join(team).join("sort.c"), code).unwrap();
+        // Not text, so it doesn't count
+        std::fs::write(sheet_dir.join(team).join("scan.pdf"), b"%PDF\0\x01\x02").unwrap();
+        grades += &format!("\n[[map]]\ntarget = \"{}\"\ngrade = \"\"\n", team);
+    }
+    std::fs::write(sheet_dir.join("grades.toml"), grades).unwrap();
+    std::fs::write(sandbox.path().join("skeleton.c"), SKELETON).unwrap();
+}
+
+#[test]
+fn similarity_finds_copied_code() {
+    let sandbox = Sandbox::new();
+    sheet(&sandbox);
+
+    let run = sandbox.kasm(&["similarity", "05", "--template", "skeleton.c"]);
+    run.success();
+    let stdout = run.stdout();
+    assert!(stdout.contains("Team A <-> Team B"), "{}", stdout);
+    assert!(stdout.contains("sort.c:"), "{}", stdout);
+    assert!(!stdout.contains("Team C"), "{}", stdout);
+    assert!(stdout.contains("1 suspicious pair(s) among 3 submissions"));
+}
+
+#[test]
+fn similarity_ignores_the_template() {
+    let sandbox = Sandbox::new();
+    sheet(&sandbox);
+
+    // The skeleton alone makes C look like A and B
+    let run = sandbox.kasm(&["similarity", "05", "--threshold", "30"]);
+    run.success();
+    assert!(run.stdout().contains("Team C"), "{}", run.stdout());
+
+    // ... unless it's configured as the sheet's template
+    let kasm_toml = sandbox.path().join("kasm.toml");
+    let mut master = std::fs::read_to_string(&kasm_toml).unwrap();
+    master.push_str("\n[similarity]\nthreshold = 30\ntemplates = [\"skeleton.c\"]\n");
+    std::fs::write(&kasm_toml, master).unwrap();
+
+    let run = sandbox.kasm_in(&sandbox.sheet_dir("05"), &["similarity"]);
+    run.success();
+    assert!(!run.stdout().contains("Team C"), "{}", run.stdout());
+    assert!(run.stdout().contains("Team A <-> Team B"));
+
+    sandbox
+        .kasm(&["similarity", "05", "--threshold", "101"])
+        .exits_with(15);
+}
+
+#[cfg(unix)]
+#[test]
+fn similarity_doesnt_follow_symlinks() {
+    let sandbox = Sandbox::new();
+    sheet(&sandbox);
+    let team_a = sandbox.sheet_dir("05").join("Team A");
+    std::os::unix::fs::symlink("..", team_a.join("up")).unwrap();
+    std::os::unix::fs::symlink("/", team_a.join("root")).unwrap();
+
+    let run = sandbox.kasm(&["similarity", "05", "--template", "skeleton.c"]);
+    run.success();
+    assert!(
+        run.stdout().contains("Team A <-> Team B"),
+        "{}",
+        run.stdout()
+    );
+    assert!(!run.stdout().contains("up/"), "{}", run.stdout());
+}